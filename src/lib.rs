@@ -0,0 +1,1516 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::f32::consts::TAU;
+#[cfg(feature = "raycast_stats")]
+use std::sync::Mutex;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub const RAYCAST_DEPTH: u32 = 100;
+pub const CORNER_EPSILON: f32 = 1e-5;
+// closest depth anything is projected at, keeps walls that touch the camera from growing to infinity
+pub const NEAR_CLIP: f32 = 1e-4;
+// radians to either side of a wall corner that visibility_polygon aims its extra rays
+pub const VISIBILITY_EPSILON: f32 = 1e-4;
+
+pub fn world_to_cell(position: Vec2) -> (i32, i32) {
+    (position.x.floor() as i32, position.y.floor() as i32)
+}
+
+pub fn column_angle(column: u32, render_width: u32, fov: f32) -> f32 {
+    let width = render_width as f32;
+    let focal = width / (2. * (fov / 2.).tan());
+    ((column as f32 - width / 2.) / focal).atan()
+}
+
+pub fn column_direction(column: u32, render_width: u32, fov: f32, rotation: f32) -> Vec2 {
+    Vec2::from_angle(column_angle(column, render_width, fov)).rotate(Vec2::from_angle(rotation))
+}
+
+pub fn ray_fan(rotation: f32, fov: f32, render_width: u32) -> impl DoubleEndedIterator<Item = (u32, Vec2)> {
+    CameraModel::PlanarColumns.ray_fan(rotation, fov, render_width)
+}
+
+pub fn angle_to_column(angle: f32, render_width: u32, fov: f32) -> f32 {
+    let width = render_width as f32;
+    let focal = width / (2. * (fov / 2.).tan());
+    angle.tan() * focal + width / 2.
+}
+
+// how screen columns map to ray directions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CameraModel {
+    // rays spread evenly across a flat camera plane, the classic Wolfenstein look: straight walls
+    // stay straight, but things stretch toward the screen edges at wide fields of view
+    #[default]
+    PlanarColumns,
+    // rays spread evenly in angle, a cylindrical projection: no stretching at the edges, but
+    // long straight walls bow outward in the middle of the screen
+    AngularColumns,
+}
+
+impl CameraModel {
+    pub fn column_angle(self, column: u32, render_width: u32, fov: f32) -> f32 {
+        match self {
+            CameraModel::PlanarColumns => column_angle(column, render_width, fov),
+            CameraModel::AngularColumns => (column as f32 / render_width as f32 - 0.5) * fov,
+        }
+    }
+
+    pub fn column_direction(self, column: u32, render_width: u32, fov: f32, rotation: f32) -> Vec2 {
+        Vec2::from_angle(self.column_angle(column, render_width, fov)).rotate(Vec2::from_angle(rotation))
+    }
+
+    // (column, direction) of the ray through every screen column, left edge first
+    pub fn ray_fan(self, rotation: f32, fov: f32, render_width: u32) -> impl DoubleEndedIterator<Item = (u32, Vec2)> {
+        (0..render_width).map(move |column| (column, self.column_direction(column, render_width, fov, rotation)))
+    }
+
+    pub fn angle_to_column(self, angle: f32, render_width: u32, fov: f32) -> f32 {
+        match self {
+            CameraModel::PlanarColumns => angle_to_column(angle, render_width, fov),
+            CameraModel::AngularColumns => (angle / fov + 0.5) * render_width as f32,
+        }
+    }
+
+    // pixels per world unit at depth 1, used for vertical scale as well so pixels stay square and a
+    // wider window only loses vertical view instead of stretching
+    pub fn focal_length(self, render_width: u32, fov: f32) -> f32 {
+        match self {
+            CameraModel::PlanarColumns => render_width as f32 / (2. * (fov / 2.).tan()),
+            CameraModel::AngularColumns => render_width as f32 / fov,
+        }
+    }
+
+    // the depth that sets on-screen size for something `distance` away at `angle` off center,
+    // the planar model takes the distance to the camera plane to avoid fisheye
+    pub fn projected_depth(self, distance: f32, angle: f32) -> f32 {
+        distance * self.depth_factor(angle)
+    }
+
+    // what projected_depth scales the distance along a ray `angle` off center by, the same for every frame
+    // so renderers can keep one per column
+    pub fn depth_factor(self, angle: f32) -> f32 {
+        match self {
+            CameraModel::PlanarColumns => angle.cos(),
+            CameraModel::AngularColumns => 1.,
+        }
+    }
+
+    // on-screen height of a 1-tall wall `distance` along a ray `angle` off center, `focal_length` being
+    // what focal_length returns for the screen
+    pub fn projected_height(self, distance: f32, angle: f32, focal_length: f32) -> f32 {
+        focal_length / self.projected_depth(distance, angle).max(NEAR_CLIP)
+    }
+}
+
+pub const CHUNK_SIZE: i32 = 16;
+
+// wall cells bucketed into CHUNK_SIZE square chunks, so the lookup for every step of a ray only searches the
+// few walls of the chunk the ray is in; serialized as a plain list of cells
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(i32, i32)>", into = "Vec<(i32, i32)>")]
+pub struct WallSet {
+    chunks: HashMap<(i32, i32), HashSet<(i32, i32)>>,
+    len: usize,
+    // kept up to date on every change, every raycast asks for it
+    bounds: Option<(IVec2, IVec2)>,
+}
+
+impl WallSet {
+    fn chunk(cell: (i32, i32)) -> (i32, i32) {
+        (cell.0.div_euclid(CHUNK_SIZE), cell.1.div_euclid(CHUNK_SIZE))
+    }
+
+    pub fn contains(&self, cell: &(i32, i32)) -> bool {
+        self.chunks.get(&Self::chunk(*cell)).is_some_and(|chunk| chunk.contains(cell))
+    }
+
+    // false if the cell was already a wall
+    pub fn insert(&mut self, cell: (i32, i32)) -> bool {
+        let inserted = self.chunks.entry(Self::chunk(cell)).or_default().insert(cell);
+        if inserted {
+            self.len += 1;
+            let cell = IVec2::new(cell.0, cell.1);
+            self.bounds = Some(self.bounds.map_or((cell, cell), |(min, max)| (min.min(cell), max.max(cell))));
+        }
+        inserted
+    }
+
+    // false if the cell wasn't a wall
+    pub fn remove(&mut self, cell: (i32, i32)) -> bool {
+        let key = Self::chunk(cell);
+        let Some(chunk) = self.chunks.get_mut(&key) else { return false };
+
+        let removed = chunk.remove(&cell);
+        if removed { self.len -= 1 }
+        if chunk.is_empty() { self.chunks.remove(&key); }
+
+        // only a cell on the edge of the bounds can shrink them
+        let on_edge = self.bounds.is_some_and(|(min, max)| cell.0 == min.x || cell.0 == max.x || cell.1 == min.y || cell.1 == max.y);
+        if removed && on_edge {
+            let mut cells = self.iter().map(|&(x, y)| IVec2::new(x, y));
+            self.bounds = cells.next().map(|first| cells.fold((first, first), |(min, max), cell| (min.min(cell), max.max(cell))));
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        *self = WallSet::default();
+    }
+
+    // smallest and largest cell on each axis (inclusive), None when empty
+    pub fn bounds(&self) -> Option<(IVec2, IVec2)> {
+        self.bounds
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &(i32, i32)> {
+        self.chunks.values().flatten()
+    }
+}
+
+impl<'a> IntoIterator for &'a WallSet {
+    type Item = &'a (i32, i32);
+    type IntoIter = std::iter::Flatten<std::collections::hash_map::Values<'a, (i32, i32), HashSet<(i32, i32)>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.chunks.values().flatten()
+    }
+}
+
+impl FromIterator<(i32, i32)> for WallSet {
+    fn from_iter<I: IntoIterator<Item = (i32, i32)>>(cells: I) -> Self {
+        let mut walls = WallSet::default();
+        for cell in cells {
+            walls.insert(cell);
+        }
+        walls
+    }
+}
+
+impl From<Vec<(i32, i32)>> for WallSet {
+    fn from(cells: Vec<(i32, i32)>) -> Self {
+        cells.into_iter().collect()
+    }
+}
+
+// sorted, so saved maps and dumps come out the same every time
+impl From<WallSet> for Vec<(i32, i32)> {
+    fn from(walls: WallSet) -> Self {
+        let mut cells: Vec<(i32, i32)> = walls.iter().copied().collect();
+        cells.sort();
+        cells
+    }
+}
+
+// build one with `new` and fill in the per-cell maps; walls only change through the methods below, which keep
+// everything that depends on them in step
+#[derive(Default, Serialize, Deserialize)]
+pub struct Environment {
+    walls: WallSet,
+    // walls without an entry here are WallKind::Solid
+    pub kinds: HashMap<(i32, i32), WallKind>,
+    // how far each door has slid open, from 0 (shut) to 1 (fully open)
+    pub door_openness: HashMap<(i32, i32), f32>,
+    // wall height in cells, walls without an entry are 1 tall
+    pub heights: HashMap<(i32, i32), f32>,
+    // per cell floor and ceiling colors, cells without an entry use the renderer's defaults
+    pub floors: HashMap<(i32, i32), Color>,
+    pub ceilings: HashMap<(i32, i32), Color>,
+    // how far the floor of a pit cell sits below the normal floor, in cells
+    pub floor_depths: HashMap<(i32, i32), f32>,
+    // how rays through this level treat exact grid corners
+    #[serde(default)]
+    pub tie_break: TieBreak,
+    // what the floor of each cell is made of, for footsteps; cells without an entry have no particular material
+    #[serde(default)]
+    pub floor_materials: HashMap<(i32, i32), Material>,
+    // walls that only exist as seen from the side they face, from anywhere else rays and movement pass through them
+    #[serde(default)]
+    pub one_sided: HashMap<(i32, i32), Normal>,
+    // baked lighting multiplied into the wall's shading, per face where one is given and per cell otherwise, 1 without either
+    #[serde(default)]
+    pub brightness: HashMap<(i32, i32), f32>,
+    #[serde(default)]
+    pub face_brightness: HashMap<((i32, i32), Normal), f32>,
+    // the flat color of walls whose kind isn't shaded, white without an entry
+    #[serde(default)]
+    pub wall_colors: HashMap<(i32, i32), Color>,
+    // how thick the fog hanging in each open cell is, per unit of ray length; cells without an entry are clear
+    #[serde(default)]
+    pub fog_density: HashMap<(i32, i32), f32>,
+}
+
+impl Environment {
+    pub fn new(walls: impl IntoIterator<Item = (i32, i32)>) -> Self {
+        Environment { walls: walls.into_iter().collect(), ..default() }
+    }
+
+    pub fn contains(&self, cell: (i32, i32)) -> bool {
+        self.walls.contains(&cell)
+    }
+
+    // in no particular order
+    pub fn iter_walls(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        self.walls.iter().copied()
+    }
+
+    pub fn wall_count(&self) -> usize {
+        self.walls.len()
+    }
+
+    pub fn kind(&self, cell: (i32, i32)) -> WallKind {
+        self.kinds.get(&cell).copied().unwrap_or_default()
+    }
+
+    pub fn height(&self, cell: (i32, i32)) -> f32 {
+        self.heights.get(&cell).copied().unwrap_or(1.)
+    }
+
+    pub fn floor_color(&self, cell: (i32, i32)) -> Option<Color> {
+        self.floors.get(&cell).copied()
+    }
+
+    pub fn ceiling_color(&self, cell: (i32, i32)) -> Option<Color> {
+        self.ceilings.get(&cell).copied()
+    }
+
+    pub fn floor_material(&self, cell: (i32, i32)) -> Option<Material> {
+        self.floor_materials.get(&cell).copied()
+    }
+
+    pub fn floor_depth(&self, cell: (i32, i32)) -> f32 {
+        self.floor_depths.get(&cell).copied().unwrap_or(0.)
+    }
+
+    pub fn fog_density(&self, cell: (i32, i32)) -> f32 {
+        self.fog_density.get(&cell).copied().unwrap_or(0.)
+    }
+
+    // the fog a ray passes through in its first `distance`, each cell's density times the length of ray inside it;
+    // free for maps without any fog
+    pub fn fog_along(&self, start_pos: Vec2, direction: Vec2, distance: f32) -> f32 {
+        if self.fog_density.is_empty() { return 0. }
+
+        let cells = cells_along_ray(start_pos, direction, distance);
+        cells.iter().enumerate().map(|(i, &(cell, near))| {
+            let far = cells.get(i + 1).map_or(distance, |next| next.1).min(distance);
+            self.fog_density(cell) * (far - near).max(0.)
+        }).sum()
+    }
+
+    pub fn openness(&self, cell: (i32, i32)) -> f32 {
+        self.door_openness.get(&cell).copied().unwrap_or(0.)
+    }
+
+    pub fn one_sided(&self, cell: (i32, i32)) -> Option<Normal> {
+        self.one_sided.get(&cell).copied()
+    }
+
+    pub fn wall_color(&self, cell: (i32, i32)) -> Color {
+        self.wall_colors.get(&cell).copied().unwrap_or(Color::WHITE)
+    }
+
+    pub fn brightness(&self, cell: (i32, i32), face: Normal) -> f32 {
+        self.face_brightness.get(&(cell, face)).or_else(|| self.brightness.get(&cell)).copied().unwrap_or(1.)
+    }
+
+    // fully open doors and windows let rays through, everything else in `walls` stops them
+    pub fn blocks_ray(&self, cell: (i32, i32)) -> bool {
+        self.walls.contains(&cell) && self.openness(cell) < 1. && self.kind(cell).blocks_ray()
+    }
+
+    // fully open doors and fog walls can be walked through, everything else in `walls` can't
+    pub fn blocks_movement(&self, cell: (i32, i32)) -> bool {
+        self.walls.contains(&cell) && self.openness(cell) < 1. && self.kind(cell).blocks_movement()
+    }
+
+    // blocks_ray for a ray entering through the face pointing `face`, one-sided walls only stop rays coming at their front
+    pub fn blocks_ray_from(&self, cell: (i32, i32), face: Normal) -> bool {
+        self.blocks_ray(cell) && self.one_sided(cell).is_none_or(|side| side == face)
+    }
+
+    // blocks_movement for something coming from `from`, one-sided walls only stop it while it's fully in front of them
+    pub fn blocks_movement_from(&self, cell: (i32, i32), from: Vec2) -> bool {
+        let (min, max) = (Vec2::new(cell.0 as f32, cell.1 as f32), Vec2::new(cell.0 as f32 + 1., cell.1 as f32 + 1.));
+
+        self.blocks_movement(cell) && match self.one_sided(cell) {
+            None => true,
+            Some(Normal::North) => from.y >= max.y,
+            Some(Normal::South) => from.y <= min.y,
+            Some(Normal::East) => from.x >= max.x,
+            Some(Normal::West) => from.x <= min.x,
+        }
+    }
+
+    // adds a wall, replacing whatever kind the cell had before
+    pub fn insert_wall(&mut self, cell: (i32, i32), kind: WallKind) {
+        self.walls.insert(cell);
+        if kind == WallKind::Solid {
+            self.kinds.remove(&cell);
+        } else {
+            self.kinds.insert(cell, kind);
+        }
+    }
+
+    // removes a wall along with its kind, height, side, lighting and door state, the cell's floor and ceiling stay
+    pub fn remove_wall(&mut self, cell: (i32, i32)) {
+        self.walls.remove(cell);
+        self.kinds.remove(&cell);
+        self.one_sided.remove(&cell);
+        self.brightness.remove(&cell);
+        self.face_brightness.retain(|&(at, _), _| at != cell);
+        self.wall_colors.remove(&cell);
+        self.heights.remove(&cell);
+        self.door_openness.remove(&cell);
+    }
+
+    // removes every wall along with what remove_wall removes, floors and ceilings stay
+    pub fn clear(&mut self) {
+        self.walls.clear();
+        self.kinds.clear();
+        self.heights.clear();
+        self.door_openness.clear();
+        self.one_sided.clear();
+        self.brightness.clear();
+        self.face_brightness.clear();
+        self.wall_colors.clear();
+    }
+
+    // smallest and largest wall cell on each axis (inclusive), None for an empty map
+    pub fn bounds(&self) -> Option<(IVec2, IVec2)> {
+        self.walls.bounds()
+    }
+
+    // surrounds the map with a ring of `kind` walls just outside its bounds, for maps that don't enclose themselves
+    pub fn add_border(&mut self, kind: WallKind) {
+        let Some((min, max)) = self.bounds() else { return };
+        let (min, max) = (min - IVec2::ONE, max + IVec2::ONE);
+
+        let horizontal = (min.x..=max.x).flat_map(|x| [(x, min.y), (x, max.y)]);
+        let vertical = (min.y + 1..max.y).flat_map(|y| [(min.x, y), (max.x, y)]);
+
+        for cell in horizontal.chain(vertical) {
+            if !self.walls.contains(&cell) {
+                self.insert_wall(cell, kind);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WallKind {
+    #[default]
+    Solid,
+    // opens by itself while the player is close, see DoorConfig
+    AutoDoor,
+    // stops movement, shots and sight like a solid wall, but is drawn see-through, see RenderConfig::glass
+    Glass,
+    // can't be walked through, but rays pass as if it weren't there, like a window ledge
+    Window,
+    // can be walked through, but stops rays, so it hides what's behind it and blocks shots
+    FogWall,
+    // a solid wall that lights itself, like a screen or lava: drawn in its flat Environment::wall_color at any distance
+    Emissive,
+}
+
+impl WallKind {
+    // None for kinds that use the generic sounds
+    pub fn material(self) -> Option<Material> {
+        match self {
+            WallKind::AutoDoor => Some(Material::Metal),
+            WallKind::Solid | WallKind::Glass | WallKind::Window | WallKind::FogWall | WallKind::Emissive => None,
+        }
+    }
+
+    pub fn blocks_ray(self) -> bool {
+        self != WallKind::Window
+    }
+
+    pub fn blocks_movement(self) -> bool {
+        self != WallKind::FogWall
+    }
+
+    // false for kinds drawn without distance shading, fog or lighting
+    pub fn shaded(self) -> bool {
+        self != WallKind::Emissive
+    }
+}
+
+// what a wall or floor is made of, which picks the sound of walking on it or shooting it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Material {
+    Stone,
+    Metal,
+    Wood,
+    Carpet,
+}
+
+impl Material {
+    pub const ALL: [Material; 4] = [Material::Stone, Material::Metal, Material::Wood, Material::Carpet];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Material::Stone => "stone",
+            Material::Metal => "metal",
+            Material::Wood => "wood",
+            Material::Carpet => "carpet",
+        }
+    }
+}
+
+// what a ray does when it passes exactly through a grid corner; only rays are affected,
+// so this changes what can be seen and shot through a corner, never where the player can walk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TieBreak {
+    // crosses the x line first and never checks the cell beside the corner on the y side, so rays
+    // graze past the corner of a wall there
+    PreferX,
+    // the same with the axes swapped
+    PreferY,
+    // checks both cells beside the corner before moving on, so diagonally touching walls are solid
+    #[default]
+    Block,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RaycastHit {
+    pub cell: (i32, i32),
+    pub distance: f32,
+    pub point: Vec2,
+    // hit point relative to the cell's lower corner, both axes in 0..=1
+    pub local: Vec2,
+    pub face: FaceAxis,
+    // which way the face the ray came through points, always back towards the ray's start
+    pub normal: Normal,
+}
+
+// which grid line the ray crossed to enter the hit cell
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaceAxis {
+    // a line of constant x, so local.y runs along the face
+    X,
+    // a line of constant y, so local.x runs along the face
+    Y,
+}
+
+// which way a wall face points, north is +y like in the top-down views
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Normal {
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Normal {
+    // the face a ray going `direction` enters a cell through after crossing a `face` grid line
+    pub fn facing(face: FaceAxis, direction: Vec2) -> Self {
+        match face {
+            FaceAxis::X if direction.x > 0. => Normal::West,
+            FaceAxis::X => Normal::East,
+            FaceAxis::Y if direction.y > 0. => Normal::South,
+            FaceAxis::Y => Normal::North,
+        }
+    }
+}
+
+impl RaycastHit {
+    fn new(cell: (i32, i32), distance: f32, face: FaceAxis, start_pos: Vec2, direction: Vec2) -> Self {
+        let point = start_pos + direction * distance;
+        let local = (point - Vec2::new(cell.0 as f32, cell.1 as f32)).clamp(Vec2::ZERO, Vec2::ONE);
+
+        RaycastHit { cell, distance, point, local, face, normal: Normal::facing(face, direction) }
+    }
+
+    // fraction along the hit face, useful as a texture u coordinate
+    pub fn face_offset(&self) -> f32 {
+        match self.face {
+            FaceAxis::X => self.local.y,
+            FaceAxis::Y => self.local.x,
+        }
+    }
+
+    // where a ray from `start_pos` going `direction` meets the line this hit's face lies on, still reported against
+    // this hit's cell; None if the ray runs parallel to the face or the face is behind the start
+    pub fn on_face(&self, start_pos: Vec2, direction: Vec2) -> Option<RaycastHit> {
+        let distance = match self.face {
+            FaceAxis::X => (self.point.x - start_pos.x) / direction.x,
+            FaceAxis::Y => (self.point.y - start_pos.y) / direction.y,
+        };
+
+        (distance.is_finite() && distance >= 0.).then(|| RaycastHit::new(self.cell, distance, self.face, start_pos, direction))
+    }
+
+    // true when the hit lands within `margin` of either end of the face
+    pub fn near_corner(&self, margin: f32) -> bool {
+        let offset = self.face_offset();
+        offset < margin || offset > 1. - margin
+    }
+}
+
+pub fn raycast(
+    environment: &Environment,
+    start_pos: Vec2,
+    direction: Vec2,
+) -> Option<RaycastHit> {
+    raycast_with(environment, start_pos, direction, |cell, face| {
+        if environment.blocks_ray_from(cell, face) { Step::Stop } else { Step::Continue }
+    }).pop()
+}
+
+// raycast along an absolute world angle, in radians counterclockwise from +x like Pose::rotation, for callers
+// that think in world directions rather than screen columns; hits further than `max_distance` count as misses
+pub fn cast_angle(environment: &Environment, origin: Vec2, world_angle: f32, max_distance: f32) -> Option<RaycastHit> {
+    raycast(environment, origin, Vec2::from_angle(world_angle)).filter(|hit| hit.distance <= max_distance)
+}
+
+pub fn raycast_all(
+    environment: &Environment,
+    start_pos: Vec2,
+    direction: Vec2,
+) -> Vec<RaycastHit> {
+    raycast_with(environment, start_pos, direction, |cell, face| {
+        if environment.blocks_ray_from(cell, face) { Step::Pass } else { Step::Continue }
+    })
+}
+
+// what a raycast_with visitor wants done with a cell
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    // nothing here, keep going
+    Continue,
+    // stop at this cell and report it as the final hit
+    Stop,
+    // report this cell as a hit but keep going, for see-through cells
+    Pass,
+}
+
+// walks the ray letting `visit` decide what every cell does, given the cell and the face the ray enters it through;
+// returns the Pass hits in order followed by the Stop hit if there was one, stops early once the ray leaves the map bounds
+pub fn raycast_with(
+    environment: &Environment,
+    start_pos: Vec2,
+    direction: Vec2,
+    mut visit: impl FnMut((i32, i32), Normal) -> Step,
+) -> Vec<RaycastHit> {
+    let mut hits = Vec::new();
+    let bounds = environment.bounds();
+    let (mut steps, mut stopped, mut ended) = (0, false, false);
+
+    walk_ray(start_pos, direction, environment.tie_break, |cell, distance, face| {
+        steps += 1;
+        match visit(cell, Normal::facing(face, direction)) {
+            Step::Continue => {}
+            Step::Pass => hits.push(RaycastHit::new(cell, distance, face, start_pos, direction)),
+            Step::Stop => {
+                hits.push(RaycastHit::new(cell, distance, face, start_pos, direction));
+                (stopped, ended) = (true, true);
+                return true;
+            }
+        }
+        ended = left_bounds(bounds, cell, direction);
+        ended
+    });
+
+    // walk_ray enters at most RAYCAST_DEPTH - 1 cells past the start one, a zero direction ray enters none
+    record_ray(steps, stopped, !ended && steps >= RAYCAST_DEPTH - 1);
+    hits
+}
+
+// totals over every raycast_with call since the last take_raycast_stats, all zero unless built with the
+// raycast_stats feature
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RaycastStats {
+    pub rays: u32,
+    // cells visited, summed over all rays
+    pub steps: u32,
+    pub max_steps: u32,
+    // rays that ended without a Stop hit
+    pub misses: u32,
+    // rays that walked all RAYCAST_DEPTH cells without hitting anything or leaving the map
+    pub capped: u32,
+}
+
+impl RaycastStats {
+    pub fn average_steps(&self) -> f32 {
+        if self.rays == 0 { 0. } else { self.steps as f32 / self.rays as f32 }
+    }
+}
+
+#[cfg(feature = "raycast_stats")]
+static RAYCAST_STATS: Mutex<RaycastStats> = Mutex::new(RaycastStats { rays: 0, steps: 0, max_steps: 0, misses: 0, capped: 0 });
+
+#[cfg(feature = "raycast_stats")]
+fn record_ray(steps: u32, stopped: bool, capped: bool) {
+    let mut stats = RAYCAST_STATS.lock().unwrap();
+    stats.rays += 1;
+    stats.steps += steps;
+    stats.max_steps = stats.max_steps.max(steps);
+    stats.misses += !stopped as u32;
+    stats.capped += capped as u32;
+}
+
+// without the feature there's nothing to count, so raycasts don't pay for the lock
+#[cfg(not(feature = "raycast_stats"))]
+fn record_ray(_steps: u32, _stopped: bool, _capped: bool) {}
+
+// the stats gathered so far, starting the count over
+#[cfg(feature = "raycast_stats")]
+pub fn take_raycast_stats() -> RaycastStats {
+    std::mem::take(&mut *RAYCAST_STATS.lock().unwrap())
+}
+
+#[cfg(not(feature = "raycast_stats"))]
+pub fn take_raycast_stats() -> RaycastStats {
+    RaycastStats::default()
+}
+
+// true once a ray is past the map bounds and heading further out, so it can't hit anything anymore;
+// saves stepping all the way to RAYCAST_DEPTH in open areas
+fn left_bounds(bounds: Option<(IVec2, IVec2)>, cell: (i32, i32), direction: Vec2) -> bool {
+    let Some((min, max)) = bounds else { return true };
+
+    (cell.0 < min.x && direction.x <= 0.) || (cell.0 > max.x && direction.x >= 0.)
+        || (cell.1 < min.y && direction.y <= 0.) || (cell.1 > max.y && direction.y >= 0.)
+}
+
+// every cell the ray passes through up to `max_distance` with the distance it enters at,
+// starting with the cell the ray starts in; both cells beside a corner the ray crosses are included
+pub fn cells_along_ray(start_pos: Vec2, direction: Vec2, max_distance: f32) -> Vec<((i32, i32), f32)> {
+    let mut cells = vec![(world_to_cell(start_pos), 0.)];
+
+    walk_ray(start_pos, direction, TieBreak::Block, |cell, distance, _| {
+        if distance > max_distance { return true }
+        cells.push((cell, distance));
+        false
+    });
+
+    cells
+}
+
+// every cell the straight line between the centers of `a` and `b` passes through, from `a` to `b` inclusive;
+// where it goes exactly through a corner both cells beside it are included, like rays with TieBreak::Block
+pub fn cells_on_line(a: (i32, i32), b: (i32, i32)) -> Vec<(i32, i32)> {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let (step_x, step_y) = (dx.signum(), dy.signum());
+    let (count_x, count_y) = (dx.abs(), dy.abs());
+
+    let mut cell = a;
+    let mut cells = vec![a];
+    let (mut taken_x, mut taken_y) = (0, 0);
+
+    while taken_x < count_x || taken_y < count_y {
+        // which grid line the line crosses next, comparing (taken + 0.5) / count for both axes without dividing
+        let next = (1 + 2 * taken_x) * count_y - (1 + 2 * taken_y) * count_x;
+
+        if next == 0 {
+            cells.push((cell.0 + step_x, cell.1));
+            cells.push((cell.0, cell.1 + step_y));
+            cell = (cell.0 + step_x, cell.1 + step_y);
+            taken_x += 1;
+            taken_y += 1;
+        }
+        else if next < 0 {
+            cell.0 += step_x;
+            taken_x += 1;
+        }
+        else {
+            cell.1 += step_y;
+            taken_y += 1;
+        }
+
+        cells.push(cell);
+    }
+
+    cells
+}
+
+// Steps through every cell the ray enters, up to RAYCAST_DEPTH, until `visit` returns true.
+fn walk_ray(
+    start_pos: Vec2,
+    direction: Vec2,
+    tie_break: TieBreak,
+    mut visit: impl FnMut((i32, i32), f32, FaceAxis) -> bool,
+) {
+    let mut current_cell = (start_pos.x.floor() as i32, start_pos.y.floor() as i32);
+
+    if direction == Vec2::ZERO { return }
+
+    if direction.x == 0.0 || direction.y == 0.0 {
+        let (position, speed, face) = if direction.x != 0.0 {
+            (start_pos.x, direction.x, FaceAxis::X)
+        } else {
+            (start_pos.y, direction.y, FaceAxis::Y)
+        };
+        // a zero component's signum is still 1, so step by the sign of the moving axis only
+        let step = if direction.x != 0.0 { (speed.signum() as i32, 0) } else { (0, speed.signum() as i32) };
+
+        // a start exactly on a grid line going backwards crosses it right away
+        let fract = position - position.floor();
+        let mut ray_length = (if speed > 0.0 { 1.0 - fract } else { fract }) / speed.abs();
+
+        for _ in 1..RAYCAST_DEPTH {
+            current_cell.0 += step.0;
+            current_cell.1 += step.1;
+
+            if visit(current_cell, ray_length, face) { return }
+
+            ray_length += 1.0 / speed.abs();
+        }
+        return;
+    }
+
+    let x_intercept = |x: i32| -> f32 {
+        let mut a = x as f32 - start_pos.fract().x;
+        if direction.x < 0.0 { a += 1.0 }
+        a / direction.x
+    };
+
+    let y_intercept = |y: i32| -> f32 {
+        let mut a = y as f32 - start_pos.fract().y;
+        if direction.y < 0.0 { a += 1.0 }
+        a / direction.y
+    };
+
+    let step_direction = (direction.signum().x as i32, direction.signum().y as i32);
+    let mut distance: f32;
+    let mut face: FaceAxis;
+
+    for _ in 1..RAYCAST_DEPTH {
+        let steps_taken = (current_cell.0 - start_pos.x.floor() as i32, current_cell.1 - start_pos.y.floor() as i32);
+
+        let x_intercept_distance = x_intercept(steps_taken.0 + step_direction.0);
+        let y_intercept_distance = y_intercept(steps_taken.1 + step_direction.1);
+
+        let corner = (x_intercept_distance - y_intercept_distance).abs() < CORNER_EPSILON;
+        // at a corner the tie-break decides, the intercepts only match to within rounding and could come out either way
+        let step_x = if corner { tie_break == TieBreak::PreferX } else { x_intercept_distance < y_intercept_distance };
+
+        if corner && tie_break == TieBreak::Block {
+            // the ray passes exactly through a cell corner, so check both cells beside
+            // the corner first, otherwise it could slip diagonally between two walls
+            distance = x_intercept_distance;
+
+            if visit((current_cell.0 + step_direction.0, current_cell.1), distance, FaceAxis::X) { return }
+            if visit((current_cell.0, current_cell.1 + step_direction.1), distance, FaceAxis::Y) { return }
+
+            // the diagonal cell only touches the ray at its corner, either face is as good
+            current_cell.0 += step_direction.0;
+            current_cell.1 += step_direction.1;
+            face = FaceAxis::X;
+        }
+        else if step_x {
+            current_cell.0 += step_direction.0;
+            distance = x_intercept_distance;
+            face = FaceAxis::X;
+        }
+        else {
+            current_cell.1 += step_direction.1;
+            distance = y_intercept_distance;
+            face = FaceAxis::Y;
+        }
+
+        if visit(current_cell, distance, face) { return }
+    }
+}
+
+// every open cell reachable from `start` without leaving the map bounds, nearest first
+pub fn open_cells(environment: &Environment, start: (i32, i32)) -> Vec<(i32, i32)> {
+    let Some((min, max)) = environment.bounds() else { return Vec::new() };
+
+    let mut visited = HashSet::from([start]);
+    let mut queue = VecDeque::from([start]);
+    let mut cells = Vec::new();
+
+    while let Some(cell) = queue.pop_front() {
+        if environment.blocks_movement(cell) { continue }
+        cells.push(cell);
+
+        for next in [(cell.0 + 1, cell.1), (cell.0 - 1, cell.1), (cell.0, cell.1 + 1), (cell.0, cell.1 - 1)] {
+            let inside = next.0 >= min.x && next.0 <= max.x && next.1 >= min.y && next.1 <= max.y;
+            if inside && visited.insert(next) { queue.push_back(next) }
+        }
+    }
+
+    cells
+}
+
+// wall cells no ray hits from the middle of any open cell reachable from `start`, `rays_per_cell` rays spread
+// all the way around each; geometry the player never gets to see, sorted
+pub fn unseen_walls(environment: &Environment, start: (i32, i32), rays_per_cell: u32) -> Vec<(i32, i32)> {
+    let mut seen = HashSet::new();
+
+    for cell in open_cells(environment, start) {
+        let center = Vec2::new(cell.0 as f32, cell.1 as f32) + Vec2::splat(0.5);
+
+        for ray in 0..rays_per_cell {
+            let direction = Vec2::from_angle(ray as f32 / rays_per_cell as f32 * TAU);
+            if let Some(hit) = raycast(environment, center, direction) {
+                seen.insert(hit.cell);
+            }
+        }
+    }
+
+    let mut unseen: Vec<(i32, i32)> = environment.iter_walls().filter(|wall| !seen.contains(wall)).collect();
+    unseen.sort();
+    unseen
+}
+
+// the open cell fewest steps away from the one `from` is in, searching outward through walls but never past
+// the map bounds; the cell itself when it's open, None when every cell inside the bounds is a wall
+pub fn nearest_open_cell(environment: &Environment, from: Vec2) -> Option<(i32, i32)> {
+    let start = world_to_cell(from);
+    if !environment.blocks_movement(start) { return Some(start) }
+
+    let (min, max) = environment.bounds()?;
+    let mut visited = HashSet::from([start]);
+    let mut queue = VecDeque::from([start]);
+
+    while let Some(cell) = queue.pop_front() {
+        if !environment.blocks_movement(cell) { return Some(cell) }
+
+        for next in [(cell.0 + 1, cell.1), (cell.0 - 1, cell.1), (cell.0, cell.1 + 1), (cell.0, cell.1 - 1)] {
+            let inside = next.0 >= min.x && next.0 <= max.x && next.1 >= min.y && next.1 <= max.y;
+            if inside && visited.insert(next) { queue.push_back(next) }
+        }
+    }
+
+    None
+}
+
+// distance along a normalized ray to where it enters a circle, None if it misses or starts inside
+pub fn ray_circle_distance(origin: Vec2, direction: Vec2, center: Vec2, radius: f32) -> Option<f32> {
+    let to_center = center - origin;
+    let along = to_center.dot(direction);
+    let miss_squared = to_center.length_squared() - along * along;
+
+    if miss_squared > radius * radius { return None }
+
+    let distance = along - (radius * radius - miss_squared).sqrt();
+    (distance >= 0.).then_some(distance)
+}
+
+// distance from a point to the nearest wall straight along each axis, None where the ray leaves
+// the map without hitting anything; north is +y like in the top-down views
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CardinalDistances {
+    pub north: Option<f32>,
+    pub south: Option<f32>,
+    pub east: Option<f32>,
+    pub west: Option<f32>,
+}
+
+pub fn cardinal_distances(environment: &Environment, position: Vec2) -> CardinalDistances {
+    let distance = |direction: Vec2| raycast(environment, position, direction).map(|hit| hit.distance);
+
+    CardinalDistances {
+        north: distance(Vec2::Y),
+        south: distance(Vec2::NEG_Y),
+        east: distance(Vec2::X),
+        west: distance(Vec2::NEG_X),
+    }
+}
+
+// true when no wall stands between the two points
+pub fn has_line_of_sight(environment: &Environment, from: Vec2, to: Vec2) -> bool {
+    let offset = to - from;
+    let distance = offset.length();
+
+    if world_to_cell(from) == world_to_cell(to) || distance == 0. {
+        return !environment.blocks_ray(world_to_cell(from));
+    }
+
+    match raycast(environment, from, offset / distance) {
+        Some(hit) => hit.distance > distance,
+        None => true,
+    }
+}
+
+// the region visible from `from`, as the vertices of a polygon ordered counterclockwise; rays are aimed at every
+// wall corner and just past either side of it, so the polygon's edges run along the wall faces and the shadows
+// they cast. rays that escape the map end RAYCAST_DEPTH away
+pub fn visibility_polygon(environment: &Environment, from: Vec2) -> Vec<Vec2> {
+    let corners: HashSet<(i32, i32)> = environment.iter_walls()
+        .flat_map(|(x, y)| [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)])
+        .collect();
+
+    let mut points: Vec<(f32, Vec2)> = corners.into_iter()
+        .flat_map(|(x, y)| {
+            let offset = Vec2::new(x as f32, y as f32) - from;
+            let angle = offset.y.atan2(offset.x);
+            [angle - VISIBILITY_EPSILON, angle, angle + VISIBILITY_EPSILON]
+        })
+        .map(|angle| {
+            let direction = Vec2::from_angle(angle);
+            let distance = raycast(environment, from, direction).map_or(RAYCAST_DEPTH as f32, |hit| hit.distance);
+            (angle.rem_euclid(TAU), from + direction * distance)
+        })
+        .collect();
+
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    points.into_iter().map(|(_, point)| point).collect()
+}
+
+// smallest and largest angle, relative to facing and positive to the left, of the part of the cell in
+// front of the viewer; None when the whole cell is behind. a cell the viewer stands in or that reaches
+// behind them is cut off at the viewer's side line, so it spans out to ±π/2. compare against fov / 2 to
+// tell if it's on screen
+pub fn angular_extent(position: Vec2, rotation: f32, cell: (i32, i32)) -> Option<(f32, f32)> {
+    let origin = Vec2::new(cell.0 as f32, cell.1 as f32);
+    // x is the distance in front of the viewer, y is to the left, same as the sprite projection
+    let corners = [Vec2::ZERO, Vec2::X, Vec2::ONE, Vec2::Y]
+        .map(|corner| (origin + corner - position).rotate(Vec2::from_angle(-rotation)));
+
+    // the corners in front, plus where the edges between a corner in front and one behind cross the side line
+    let mut points = Vec::new();
+    for (i, &corner) in corners.iter().enumerate() {
+        let next = corners[(i + 1) % corners.len()];
+        if corner.x > 0. { points.push(corner) }
+        if (corner.x > 0.) != (next.x > 0.) {
+            let t = corner.x / (corner.x - next.x);
+            points.push(Vec2::new(0., corner.y + (next.y - corner.y) * t));
+        }
+    }
+
+    points.into_iter()
+        .map(|local| local.y.atan2(local.x))
+        .fold(None, |extent, angle| match extent {
+            Some((min, max)) => Some((angle.min(min), angle.max(max))),
+            None => Some((angle, angle)),
+        })
+}
+
+// wall cells hit by a fan of `ray_count` rays spread over `fov`, ignoring hits past `max_distance`
+pub fn visible_cells(
+    environment: &Environment,
+    position: Vec2,
+    rotation: f32,
+    fov: f32,
+    max_distance: f32,
+    ray_count: u32,
+) -> HashSet<(i32, i32)> {
+    ray_fan(rotation, fov, ray_count)
+        .filter_map(|(_, direction)| raycast(environment, position, direction))
+        .filter(|hit| hit.distance <= max_distance)
+        .map(|hit| hit.cell)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    const WIDTH: u32 = 320;
+    const FOV: f32 = FRAC_PI_2;
+    const MODELS: [CameraModel; 2] = [CameraModel::PlanarColumns, CameraModel::AngularColumns];
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn edge_columns_are_half_the_fov_off_center() {
+        for model in MODELS {
+            assert!(approx(model.column_angle(0, WIDTH, FOV), -FOV / 2.), "{model:?}");
+            assert!(approx(model.angle_to_column(-FOV / 2., WIDTH, FOV), 0.), "{model:?}");
+            assert!(approx(model.angle_to_column(FOV / 2., WIDTH, FOV), WIDTH as f32), "{model:?}");
+
+            // the last column starts one column short of the right edge
+            let last = model.column_angle(WIDTH - 1, WIDTH, FOV);
+            assert!(last < FOV / 2. && last > FOV / 2. - FOV / WIDTH as f32 - 1e-4, "{model:?}: {last}");
+        }
+    }
+
+    #[test]
+    fn center_column_looks_straight_ahead() {
+        for model in MODELS {
+            assert_eq!(model.column_angle(WIDTH / 2, WIDTH, FOV), 0., "{model:?}");
+            assert_eq!(model.angle_to_column(0., WIDTH, FOV), WIDTH as f32 / 2., "{model:?}");
+        }
+    }
+
+    #[test]
+    fn columns_round_trip_through_angles() {
+        for model in MODELS {
+            for column in 0..WIDTH {
+                let angle = model.column_angle(column, WIDTH, FOV);
+                assert_eq!(model.angle_to_column(angle, WIDTH, FOV).round() as u32, column, "{model:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn angles_outside_the_fov_land_outside_the_screen() {
+        for model in MODELS {
+            assert!(model.angle_to_column(-FOV / 2. - 1e-3, WIDTH, FOV) < 0., "{model:?}");
+            assert!(model.angle_to_column(FOV / 2. + 1e-3, WIDTH, FOV) >= WIDTH as f32, "{model:?}");
+
+            let past = model.column_angle(WIDTH + 10, WIDTH, FOV);
+            assert!(past > FOV / 2., "{model:?}: {past}");
+            assert_eq!(model.angle_to_column(past, WIDTH, FOV).round() as u32, WIDTH + 10, "{model:?}");
+        }
+    }
+
+    #[test]
+    fn diagonal_gap_between_walls_blocks_rays() {
+        let environment = Environment::new([(1, 0), (0, 1)]);
+        let hit = raycast(&environment, Vec2::splat(0.5), Vec2::ONE.normalize()).expect("ray slipped through the corner");
+
+        assert!([(1, 0), (0, 1)].contains(&hit.cell), "{hit:?}");
+        assert!(approx(hit.distance, 0.5_f32.hypot(0.5)), "{hit:?}");
+    }
+
+    #[test]
+    fn hits_report_where_they_land_in_the_cell() {
+        let east = raycast(&Environment::new([(2, 1)]), Vec2::splat(0.5), Vec2::new(1.5, 0.75).normalize()).unwrap();
+        assert_eq!((east.cell, east.face, east.normal), ((2, 1), FaceAxis::X, Normal::West));
+        assert!(approx(east.local.x, 0.) && approx(east.local.y, 0.25), "{east:?}");
+        assert!(approx(east.face_offset(), 0.25));
+
+        let north = raycast(&Environment::new([(0, 2)]), Vec2::new(0.75, 0.5), Vec2::Y).unwrap();
+        assert_eq!((north.cell, north.face, north.normal), ((0, 2), FaceAxis::Y, Normal::South));
+        assert!(approx(north.local.x, 0.75) && approx(north.local.y, 0.), "{north:?}");
+        assert!(approx(north.face_offset(), 0.75));
+
+        let west = raycast(&Environment::new([(0, 0)]), Vec2::new(2.5, 0.3), Vec2::NEG_X).unwrap();
+        assert_eq!((west.cell, west.face, west.normal), ((0, 0), FaceAxis::X, Normal::East));
+        assert!(approx(west.local.x, 1.) && approx(west.local.y, 0.3), "{west:?}");
+        assert!(!west.near_corner(0.05) && north.near_corner(0.3));
+    }
+
+    #[test]
+    fn window_aspect_only_changes_the_vertical_extent() {
+        for model in MODELS {
+            // half the screen height at depth 1 over the focal length, the tangent of the vertical half view
+            let vertical = |width: u32, height: u32| height as f32 / 2. / model.focal_length(width, FOV);
+
+            for (width, height) in [(640, 480), (1280, 720)] {
+                assert!(approx(model.column_angle(0, width, FOV), -FOV / 2.), "{model:?} {width}x{height}");
+                assert!(approx(model.angle_to_column(FOV / 2., width, FOV), width as f32), "{model:?} {width}x{height}");
+            }
+
+            assert!(approx(vertical(640, 480) / vertical(1280, 720), (3. / 4.) / (9. / 16.)), "{model:?}");
+        }
+
+        // a wall one unit away fills the same share of a 4:3 and a 16:9 screen's width
+        let planar = CameraModel::PlanarColumns;
+        assert!(approx(320. / planar.focal_length(640, FOV), 640. / planar.focal_length(1280, FOV)));
+    }
+
+    #[test]
+    fn angular_extent_of_cells_around_the_viewer() {
+        let viewer = Vec2::splat(0.5);
+        let extent = |cell| angular_extent(viewer, 0., cell);
+
+        let (min, max) = extent((2, 0)).unwrap();
+        assert!(approx(min, -(0.5_f32 / 1.5).atan()) && approx(max, (0.5_f32 / 1.5).atan()), "{min} {max}");
+
+        let (min, max) = extent((1, 2)).unwrap();
+        assert!(approx(min, FRAC_PI_2 / 2.) && approx(max, 2.5_f32.atan2(0.5)), "{min} {max}");
+
+        // straight to the left, half of it behind the viewer
+        let (min, max) = extent((0, 2)).unwrap();
+        assert!(approx(min, 1.5_f32.atan2(0.5)) && approx(max, FRAC_PI_2), "{min} {max}");
+
+        let (min, max) = extent((0, 0)).unwrap();
+        assert!(approx(min, -FRAC_PI_2) && approx(max, FRAC_PI_2), "{min} {max}");
+
+        assert_eq!(extent((-2, 0)), None);
+
+        // turning to face the cell brings it to the middle
+        let (min, max) = angular_extent(viewer, FRAC_PI_2, (0, 2)).unwrap();
+        assert!(approx(min, -max) && max > 0., "{min} {max}");
+    }
+
+    #[test]
+    fn ray_fan_is_symmetric_and_spans_the_fov() {
+        let rotation = 0.7;
+        let facing = Vec2::from_angle(rotation);
+
+        for model in MODELS {
+            let fan: Vec<(u32, Vec2)> = model.ray_fan(rotation, FOV, WIDTH).collect();
+            assert_eq!(fan.len(), WIDTH as usize);
+            assert!(fan.iter().enumerate().all(|(i, &(column, _))| column == i as u32));
+
+            let angles: Vec<f32> = fan.iter().map(|&(_, direction)| facing.angle_between(direction)).collect();
+            assert!(approx(angles[0], -FOV / 2.), "{model:?}: {}", angles[0]);
+            assert!(approx(angles[WIDTH as usize / 2], 0.), "{model:?}");
+
+            // column c and column width - c sit the same angle either side of straight ahead
+            for column in 1..WIDTH as usize {
+                assert!(approx(angles[column], -angles[WIDTH as usize - column]), "{model:?}: column {column}");
+            }
+            assert!(angles.windows(2).all(|pair| pair[0] < pair[1]), "{model:?}");
+        }
+    }
+
+    #[test]
+    fn windows_and_fog_walls_split_sight_from_movement() {
+        let mut environment = Environment::new([(3, 0)]);
+        environment.insert_wall((1, 0), WallKind::Window);
+
+        assert_eq!(raycast(&environment, Vec2::splat(0.5), Vec2::X).map(|hit| hit.cell), Some((3, 0)));
+        assert!(!environment.blocks_ray((1, 0)) && environment.blocks_movement((1, 0)));
+
+        environment.insert_wall((1, 0), WallKind::FogWall);
+
+        assert_eq!(raycast(&environment, Vec2::splat(0.5), Vec2::X).map(|hit| hit.cell), Some((1, 0)));
+        assert!(environment.blocks_ray((1, 0)) && !environment.blocks_movement((1, 0)));
+    }
+
+    #[test]
+    fn tie_break_picks_which_cells_beside_a_corner_are_checked() {
+        // a row of walls further out stops rays that get past the corner
+        let far_row = || (-5..50).map(|x| (x, 5));
+        let cast = |walls: &[(i32, i32)], tie_break: TieBreak, start: Vec2| {
+            let mut environment = Environment::new(walls.iter().copied().chain(far_row()));
+            environment.tie_break = tie_break;
+            // straight through the corner at (1, 1)
+            raycast(&environment, start, (Vec2::ONE - start).normalize()).map(|hit| hit.cell)
+        };
+
+        // a wall beside the corner on the x side, the y side or both
+        let (x_side, y_side, both) = ([(1, 0)].as_slice(), [(0, 1)].as_slice(), [(1, 0), (0, 1)].as_slice());
+
+        // starts all over the cell, the intercepts of the two grid lines through the corner only match to within rounding
+        let starts = (1..10).flat_map(|x| (1..10).map(move |y| Vec2::new(x as f32, y as f32) / 10.));
+
+        for start in starts {
+            let past = |cell: Option<(i32, i32)>| cell.is_some_and(|cell| cell.1 == 5);
+
+            assert_eq!(cast(x_side, TieBreak::Block, start), Some((1, 0)), "{start:?}");
+            assert_eq!(cast(y_side, TieBreak::Block, start), Some((0, 1)), "{start:?}");
+            assert!(matches!(cast(both, TieBreak::Block, start), Some((1, 0) | (0, 1))), "{start:?}");
+
+            assert_eq!(cast(x_side, TieBreak::PreferX, start), Some((1, 0)), "{start:?}");
+            assert!(past(cast(y_side, TieBreak::PreferX, start)), "{start:?}");
+            assert_eq!(cast(both, TieBreak::PreferX, start), Some((1, 0)), "{start:?}");
+
+            assert!(past(cast(x_side, TieBreak::PreferY, start)), "{start:?}");
+            assert_eq!(cast(y_side, TieBreak::PreferY, start), Some((0, 1)), "{start:?}");
+            assert_eq!(cast(both, TieBreak::PreferY, start), Some((0, 1)), "{start:?}");
+        }
+    }
+
+    #[test]
+    fn projected_height_follows_distance() {
+        for model in MODELS {
+            let focal = model.focal_length(WIDTH, FOV);
+
+            assert!(approx(model.projected_height(1., 0., focal), focal), "{model:?}");
+            assert!(approx(model.projected_height(4., 0.3, focal) * 2., model.projected_height(2., 0.3, focal)), "{model:?}");
+            // a wall touching the camera is clipped instead of growing without bound
+            assert!(approx(model.projected_height(0., 0., focal), focal / NEAR_CLIP), "{model:?}");
+        }
+
+        // the planar model sizes by depth, so a flat wall 2 ahead is the same height across the screen
+        let planar = CameraModel::PlanarColumns;
+        let focal = planar.focal_length(WIDTH, FOV);
+        let angle = 0.6_f32;
+        assert!(approx(planar.projected_height(2. / angle.cos(), angle, focal), planar.projected_height(2., 0., focal)));
+    }
+
+    // the ring of walls around an open room covering the cells from (0, 0) to (width - 1, height - 1)
+    fn room(width: i32, height: i32) -> Environment {
+        let horizontal = (-1..=width).flat_map(|x| [(x, -1), (x, height)]);
+        let vertical = (0..height).flat_map(|y| [(-1, y), (width, y)]);
+        Environment::new(horizontal.chain(vertical))
+    }
+
+    #[test]
+    fn cardinal_distances_in_a_small_room() {
+        let distances = cardinal_distances(&room(5, 4), Vec2::new(1.5, 1.25));
+        assert_eq!(distances, CardinalDistances { north: Some(2.75), south: Some(1.25), east: Some(3.5), west: Some(1.5) });
+
+        // a doorway in the east wall lets that ray out of the map
+        let mut environment = room(5, 4);
+        environment.remove_wall((5, 1));
+        let distances = cardinal_distances(&environment, Vec2::new(1.5, 1.25));
+        assert_eq!(distances.east, None);
+        assert_eq!(distances.west, Some(1.5));
+    }
+
+    #[test]
+    fn axis_aligned_rays_from_a_grid_line_going_back() {
+        let environment = Environment::new([(1, 0), (4, 0)]);
+
+        let west = raycast(&environment, Vec2::new(2., 0.5), Vec2::NEG_X).unwrap();
+        assert_eq!((west.cell, west.distance, west.normal), ((1, 0), 0., Normal::East));
+
+        let east = raycast(&environment, Vec2::new(2., 0.5), Vec2::X).unwrap();
+        assert_eq!((east.cell, east.distance), ((4, 0), 2.));
+    }
+
+    #[test]
+    fn axis_aligned_rays_measure_in_direction_lengths() {
+        let environment = Environment::new([(3, 0), (0, -3)]);
+
+        // distances count how many times the direction fits, the same as for diagonal rays
+        let east = raycast(&environment, Vec2::splat(0.5), Vec2::new(0.5, 0.)).unwrap();
+        assert_eq!((east.cell, east.distance, east.point), ((3, 0), 5., Vec2::new(3., 0.5)));
+
+        let south = raycast(&environment, Vec2::splat(0.5), Vec2::new(0., -4.)).unwrap();
+        assert_eq!((south.cell, south.distance, south.point), ((0, -3), 0.625, Vec2::new(0.5, -2.)));
+    }
+
+    #[test]
+    fn zero_direction_rays_go_nowhere() {
+        let environment = room(3, 3);
+        assert_eq!(raycast(&environment, Vec2::splat(1.5), Vec2::ZERO), None);
+        assert_eq!(cells_along_ray(Vec2::splat(1.5), Vec2::ZERO, 10.), vec![((1, 1), 0.)]);
+    }
+
+    #[test]
+    fn one_sided_walls_only_block_from_the_front() {
+        let mut environment = Environment::new([(0, 0), (0, 3), (0, -3)]);
+        environment.one_sided.insert((0, 0), Normal::North);
+
+        // coming down from the north the ray meets the wall's front
+        let from_front = raycast(&environment, Vec2::new(0.5, 2.5), Vec2::NEG_Y).unwrap();
+        assert_eq!((from_front.cell, from_front.normal), ((0, 0), Normal::North));
+
+        // from the south it passes straight through to the wall beyond
+        let from_back = raycast(&environment, Vec2::new(0.5, -2.5), Vec2::Y).unwrap();
+        assert_eq!(from_back.cell, (0, 3));
+
+        assert!(environment.blocks_movement_from((0, 0), Vec2::new(0.5, 1.2)));
+        assert!(!environment.blocks_movement_from((0, 0), Vec2::new(0.5, -0.2)));
+        // already partly inside, so it's not in front anymore and can leave either way
+        assert!(!environment.blocks_movement_from((0, 0), Vec2::new(0.5, 0.5)));
+    }
+
+    #[test]
+    fn cells_on_straight_and_diagonal_lines() {
+        assert_eq!(cells_on_line((0, 0), (3, 0)), vec![(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(cells_on_line((2, 1), (2, -1)), vec![(2, 1), (2, 0), (2, -1)]);
+        assert_eq!(cells_on_line((4, 4), (4, 4)), vec![(4, 4)]);
+
+        // exactly through the corners, so both cells beside each one are included
+        assert_eq!(cells_on_line((0, 0), (2, 2)), vec![(0, 0), (1, 0), (0, 1), (1, 1), (2, 1), (1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn cells_on_a_shallow_line_touch_every_crossed_cell() {
+        let cells = cells_on_line((0, 0), (4, 1));
+        assert_eq!(cells, vec![(0, 0), (1, 0), (2, 0), (2, 1), (3, 1), (4, 1)]);
+        // every step moves to a neighbouring cell, so nothing the line crosses is skipped
+        assert!(cells.windows(2).all(|pair| (pair[0].0 - pair[1].0).abs() + (pair[0].1 - pair[1].1).abs() == 1));
+    }
+
+    #[test]
+    fn reversed_lines_cover_the_same_cells() {
+        for (a, b) in [((0, 0), (3, 1)), ((-2, 5), (1, -1)), ((0, 0), (2, 2)), ((1, 3), (1, -2))] {
+            let mut forward = cells_on_line(a, b);
+            let mut backward = cells_on_line(b, a);
+            assert_eq!((forward[0], backward[0]), (a, b));
+
+            forward.sort();
+            backward.sort();
+            assert_eq!(forward, backward, "{a:?} to {b:?}");
+        }
+    }
+
+    // per-frame cost of each camera model, with and without casting; run with
+    // cargo test --release camera_model_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn camera_model_cost() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        let environment = room(32, 32);
+        let frames = 500;
+
+        for model in MODELS {
+            let focal = model.focal_length(WIDTH, FOV);
+
+            let start = Instant::now();
+            for frame in 0..frames {
+                for (column, direction) in model.ray_fan(frame as f32 * 0.01, FOV, WIDTH) {
+                    black_box((direction, model.projected_height(black_box(3.), model.column_angle(column, WIDTH, FOV), focal)));
+                }
+            }
+            let projection = start.elapsed() / frames;
+
+            let start = Instant::now();
+            for frame in 0..frames {
+                for (column, direction) in model.ray_fan(frame as f32 * 0.01, FOV, WIDTH) {
+                    let hit = raycast(&environment, Vec2::splat(16.5), direction).unwrap();
+                    black_box(model.projected_height(hit.distance, model.column_angle(column, WIDTH, FOV), focal));
+                }
+            }
+            let casting = start.elapsed() / frames;
+
+            println!("{model:?}: {projection:?} projecting, {casting:?} casting and projecting, per {WIDTH} column frame");
+        }
+    }
+
+    // even-odd test against the polygon's edges
+    fn polygon_contains(polygon: &[Vec2], point: Vec2) -> bool {
+        let mut inside = false;
+        for (i, &a) in polygon.iter().enumerate() {
+            let b = polygon[(i + 1) % polygon.len()];
+            if (a.y > point.y) != (b.y > point.y) && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    #[test]
+    fn visibility_polygon_in_a_room_with_a_pillar() {
+        let mut environment = room(7, 7);
+        environment.insert_wall((4, 3), WallKind::Solid);
+        let from = Vec2::new(1.5, 3.5);
+
+        let polygon = visibility_polygon(&environment, from);
+        assert!(polygon.iter().all(|point| point.cmpge(Vec2::splat(-1e-3)).all() && point.cmple(Vec2::splat(7. + 1e-3)).all()));
+
+        // counterclockwise around the viewer
+        let angles: Vec<f32> = polygon.iter().map(|point| (*point - from).y.atan2((*point - from).x).rem_euclid(TAU)).collect();
+        assert!(angles.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        assert!(polygon_contains(&polygon, Vec2::new(3.5, 3.5)), "in front of the pillar");
+        assert!(polygon_contains(&polygon, Vec2::new(6.5, 0.5)), "past the pillar's shadow");
+        assert!(polygon_contains(&polygon, Vec2::new(0.5, 6.5)), "behind the viewer");
+        assert!(!polygon_contains(&polygon, Vec2::new(5.5, 3.5)), "right behind the pillar");
+        assert!(!polygon_contains(&polygon, Vec2::new(6.5, 3.8)), "in the pillar's shadow");
+    }
+
+    #[test]
+    fn visible_cells_in_a_room_with_a_pillar() {
+        let mut environment = room(7, 7);
+        environment.insert_wall((4, 3), WallKind::Solid);
+        let from = Vec2::new(1.5, 3.5);
+
+        let cells = visible_cells(&environment, from, 0., FOV, RAYCAST_DEPTH as f32, 200);
+        assert!(cells.contains(&(4, 3)));
+        assert!(cells.contains(&(7, 0)) && cells.contains(&(7, 6)));
+        // straight behind the pillar
+        assert!(!cells.contains(&(7, 3)));
+        // behind the viewer
+        assert!(!cells.contains(&(-1, 3)));
+
+        let near = visible_cells(&environment, from, 0., FOV, 3., 200);
+        assert_eq!(near, HashSet::from([(4, 3)]));
+    }
+
+    #[test]
+    fn cast_angle_hits_a_known_wall() {
+        let environment = room(5, 4);
+        let origin = Vec2::new(1.5, 1.5);
+
+        // the east wall's face is at x = 5, the north wall's at y = 4
+        let east = cast_angle(&environment, origin, 0., f32::INFINITY).unwrap();
+        assert!(approx(east.distance, 3.5));
+        assert_eq!(east.cell, (5, 1));
+        let north = cast_angle(&environment, origin, FRAC_PI_2, f32::INFINITY).unwrap();
+        assert!(approx(north.distance, 2.5));
+        assert_eq!(north.cell, (1, 4));
+
+        // walls past max_distance are out of reach
+        assert!(cast_angle(&environment, origin, 0., 3.).is_none());
+        assert!(cast_angle(&environment, origin, 0., 3.5).is_some());
+    }
+
+    // how much the left_bounds early-out saves on rays that leave the map, run with
+    // cargo test --release bounds_early_out_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bounds_early_out_cost() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        // a room with its east wall missing, looked out of through the gap
+        let mut environment = room(32, 32);
+        for y in 0..32 { environment.remove_wall((32, y)) }
+        let origin = Vec2::new(8.5, 16.5);
+        let frames = 500;
+
+        let start = Instant::now();
+        for _ in 0..frames {
+            for (_, direction) in CameraModel::PlanarColumns.ray_fan(0., FOV, WIDTH) {
+                black_box(raycast(&environment, origin, direction));
+            }
+        }
+        let with_early_out = start.elapsed() / frames;
+
+        // the same walk without the bounds check, so misses step all RAYCAST_DEPTH cells
+        let start = Instant::now();
+        for _ in 0..frames {
+            for (_, direction) in CameraModel::PlanarColumns.ray_fan(0., FOV, WIDTH) {
+                let mut hit = None;
+                walk_ray(origin, direction, environment.tie_break, |cell, distance, _| {
+                    if !environment.blocks_ray(cell) { return false }
+                    hit = Some(distance);
+                    true
+                });
+                black_box(hit);
+            }
+        }
+        let without_early_out = start.elapsed() / frames;
+
+        println!("{with_early_out:?} with the bounds early-out, {without_early_out:?} without, per {WIDTH} column frame");
+    }
+
+    // the chunked WallSet against one flat HashSet of cells on a big map, run with
+    // cargo test --release wall_set_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn wall_set_cost() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::hint::black_box;
+        use std::time::{Duration, Instant};
+
+        // a 256 by 256 cell maze carved by a depth first walk, rooms on odd cells with walls between them
+        let size = 256;
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut open = HashSet::from([(1, 1)]);
+        let mut stack = vec![(1, 1)];
+        while let Some(&(x, y)) = stack.last() {
+            let next: Vec<(i32, i32)> = [(2, 0), (-2, 0), (0, 2), (0, -2)].into_iter()
+                .map(|(dx, dy)| (x + dx, y + dy))
+                .filter(|&(x, y)| x > 0 && y > 0 && x < size - 1 && y < size - 1 && !open.contains(&(x, y)))
+                .collect();
+            let Some(&(next_x, next_y)) = next.get(rng.gen_range(0..next.len().max(1))) else { stack.pop(); continue };
+            open.extend([((x + next_x) / 2, (y + next_y) / 2), (next_x, next_y)]);
+            stack.push((next_x, next_y));
+        }
+        let cells: Vec<(i32, i32)> = (0..size).flat_map(|x| (0..size).map(move |y| (x, y))).filter(|cell| !open.contains(cell)).collect();
+        let chunked: WallSet = cells.iter().copied().collect();
+        let flat: HashSet<(i32, i32)> = cells.into_iter().collect();
+
+        let origins: Vec<Vec2> = (0..64).map(|_| Vec2::new(rng.gen_range(0..size / 2) as f32, rng.gen_range(0..size / 2) as f32) * 2. + 1.5).collect();
+        let frames = 20;
+
+        // a frame of rays from every origin, each stopping at the first wall
+        fn cast(origins: &[Vec2], frames: u32, contains: impl Fn(&(i32, i32)) -> bool) -> Duration {
+            let start = Instant::now();
+            for _ in 0..frames {
+                for &origin in origins {
+                    for (_, direction) in CameraModel::PlanarColumns.ray_fan(0., FOV, WIDTH) {
+                        walk_ray(origin, direction, TieBreak::default(), |cell, distance, _| {
+                            black_box(distance);
+                            contains(&cell)
+                        });
+                    }
+                }
+            }
+            start.elapsed() / frames
+        }
+
+        let chunked_time = cast(&origins, frames, |cell| chunked.contains(cell));
+        let flat_time = cast(&origins, frames, |cell| flat.contains(cell));
+        println!("{} walls: {chunked_time:?} chunked, {flat_time:?} flat, per {} rays", flat.len(), origins.len() as u32 * WIDTH);
+    }
+}
@@ -1,203 +1,4107 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::f32::consts::{PI, TAU};
+use bevy::audio::Volume;
+use bevy::input::mouse::MouseMotion;
 use bevy::prelude::*;
+use bevy::window::{CursorGrabMode, PresentMode, PrimaryWindow, WindowFocused};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use raycast_test::{cast_angle, cells_along_ray, has_line_of_sight, nearest_open_cell, open_cells, ray_circle_distance, raycast, raycast_all, take_raycast_stats, unseen_walls, visibility_polygon, visible_cells, world_to_cell, NEAR_CLIP, RAYCAST_DEPTH, CameraModel, Environment, Material, Normal, RaycastHit, RaycastStats, WallKind};
 
-pub const RAYCAST_DEPTH: u32 = 100;
-pub const FOV: f32 = PI / 2.;
-pub const DEBUG_MAP_MODE: bool = false;
-pub const PLAYER_SPEED: f32 = 3.;
-pub const PLAYER_TURNING_SPEED: f32 = PI;
+pub const CONFIG_PATH: &str = "config.ron";
+pub const CAMERA_PATH_PATH: &str = "camera_path.ron";
+pub const DEBUG_XRAY_MODE: bool = false;
+pub const DEBUG_MAP_FILLED_WALLS: bool = false;
+pub const DEBUG_WALL_KIND_LABEL: bool = false;
+pub const DEBUG_VISIBLE_CELLS: bool = false;
+pub const DEBUG_SELECTED_RAY: bool = false;
+pub const DEBUG_VISIBILITY_POLYGON: bool = false;
+pub const PLAYER_RADIUS: f32 = 0.2;
+pub const MAX_PARTICLES: usize = 256;
+pub const PARTICLE_LIFETIME: f32 = 0.4;
+pub const PARTICLE_SIZE: f32 = 0.03;
+pub const ENEMY_HEIGHT: f32 = 0.8;
+pub const ITEM_SIZE: f32 = 0.3;
+pub const PROJECTILE_SIZE: f32 = 0.08;
+pub const MIN_STRIP_HEIGHT: f32 = 1.;
 
 fn main() {
     App::new()
-        .add_plugins(DefaultPlugins)
-        .add_systems(Startup, setup)
-        .add_systems(Update, (draw_scene, update_player))
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            // on the web the game draws into the page's canvas and follows its size, ignored on desktop
+            primary_window: Some(Window { fit_canvas_to_parent: true, ..default() }),
+            ..default()
+        }))
+        .init_resource::<DebugColors>()
+        .init_resource::<ColumnTable>()
+        .init_resource::<DoorConfig>()
+        .init_resource::<WeaponConfig>()
+        .init_resource::<CameraRollConfig>()
+        .init_resource::<ScreenShake>()
+        .init_resource::<CrosshairConfig>()
+        .init_resource::<RngConfig>()
+        .init_resource::<CursorCapture>()
+        .init_resource::<AudioConfig>()
+        .init_resource::<TimeScale>()
+        .init_resource::<SelectedRay>()
+        .init_resource::<HeadBob>()
+        .init_resource::<Walked>()
+        .init_resource::<GridStep>()
+        .init_resource::<RaycastStatsLog>()
+        .init_resource::<Inventory>()
+        .init_resource::<FrozenView>()
+        .init_resource::<CameraPath>()
+        .add_event::<ParticleBurst>()
+        .add_event::<TriggerEntered>()
+        .add_event::<DamageEvent>()
+        .add_event::<SwitchLevel>()
+        .add_event::<ShotFired>()
+        .add_event::<WallImpact>()
+        .add_event::<DoorMoved>()
+        .add_event::<ItemPickedUp>()
+        .add_systems(PreStartup, apply_config)
+        .add_systems(Startup, ((setup, (spawn_enemies, spawn_items)).chain(), load_sounds))
+        .add_systems(Update, (draw_scene, draw_particles.after(draw_scene), draw_enemies.after(draw_scene), draw_items.after(draw_scene), draw_projectiles.after(draw_scene), draw_crosshair.after(draw_enemies).after(draw_particles).after(draw_items).after(draw_projectiles), update_wall_kind_label.after(draw_scene), draw_selected_ray.after(draw_scene), draw_minimap.after(draw_crosshair), update_compass, update_ascii_view))
+        .add_systems(Update, (update_player, update_second_player, mouse_look.after(update_player).before(update_cursor_capture), assist_aim.after(mouse_look), follow_camera_path.after(assist_aim).after(collide_with_entities), collide_with_entities.after(update_player), track_walked.after(collide_with_entities), update_head_bob.after(track_walked), update_cursor_capture))
+        .add_systems(Update, (toggle_render_options, adjust_time_scale, select_debug_ray, teleport_to_cursor.before(update_player), print_ascii_view, dump_state, report_coverage, toggle_frozen_view))
+        .add_systems(Update, (animate_auto_doors, switch_level, fire_weapon, step_projectiles, apply_damage, chase_player, shake_on_damage, update_screen_shake, spawn_particles, update_particles, update_triggers, log_triggers, collect_items))
+        .add_systems(Update, (play_sound_effects, play_footsteps.after(track_walked)))
+        .add_systems(Update, apply_present_mode)
+        .add_systems(Last, (log_raycast_stats, limit_frame_rate))
         .run();
 }
 
-fn setup(mut commands: Commands) {
+fn setup(mut commands: Commands, rng_config: Res<RngConfig>, render_config: Res<RenderConfig>) {
+    commands.insert_resource(GameRng(StdRng::seed_from_u64(rng_config.seed)));
     commands.spawn(Camera2dBundle::default());
-    commands.spawn((Player{}, Transform{position: Vec2::new(0.0, 0.0), rotation: 0.0}));
-    commands.spawn(Environment{ walls: vec![(-3,-3), (-2,-3), (-1,-3), (-1, -4), (0, -4), (1, -4), (2, -4), (2, -3), (2, -2), (3, -2), (3, -1), (3, 0), (3, 1), (3, 2), (2, 2), (1, 2), (0, 2), (-1, 2), (-2, 2), (-3, 2), (-3, 1), (-3, 0), (-3, -1), (-3, -2)] });
+    commands.spawn((Player{}, Viewer{slot: 0}, Pose{position: Vec2::new(0.0, 0.0), rotation: 0.0}, Elevation{height: 0.0}, CameraRoll{angle: 0.0}, DepthBuffer::default(), WallKindBuffer::default()));
+    if render_config.split_screen {
+        // starts back to back with the first player so the two views differ
+        commands.spawn((SecondPlayer{}, Viewer{slot: 1}, Pose{position: Vec2::new(0.0, 0.0), rotation: PI}, Elevation{height: 0.0}, CameraRoll{angle: 0.0}, DepthBuffer::default(), WallKindBuffer::default()));
+    }
+    commands.insert_resource(sample_levels());
+    commands.spawn(TriggerRegion{ level: 0, min: IVec2::new(0, 3), max: IVec2::new(0, 4) });
+    commands.spawn((WallKindLabel{}, TextBundle::from_section("", TextStyle::default()).with_style(Style {
+        position_type: PositionType::Absolute,
+        top: Val::Px(5.),
+        left: Val::Px(5.),
+        ..default()
+    })));
+    commands.spawn((Compass{}, TextBundle::from_section("", TextStyle::default()).with_style(Style {
+        position_type: PositionType::Absolute,
+        ..default()
+    })));
+    commands.spawn((AsciiView{}, TextBundle::from_section("", TextStyle::default()).with_style(Style {
+        position_type: PositionType::Absolute,
+        top: Val::Px(30.),
+        left: Val::Px(5.),
+        ..default()
+    })));
+}
+
+// the levels the game starts with, borders already added
+fn sample_levels() -> Levels {
+    let mut first = Environment::new([(-3,-3), (-2,-3), (-1,-3), (-1, -4), (0, -4), (1, -4), (2, -4), (2, -3), (2, -2), (3, -2), (3, -1), (3, 0), (3, 1), (3, 2), (2, 2), (1, 2), (0, 2), (-1, 2), (-2, 2), (-3, 2), (-3, 1), (-3, 0), (-3, -1), (-3, -2), (-1, 3), (1, 3), (-1, 4), (1, 4), (0, 5)]);
+    first.kinds = HashMap::from([((0, 2), WallKind::AutoDoor), ((0, 5), WallKind::Emissive)]);
+    first.heights = HashMap::from([((0, 5), 2.)]);
+    first.floors = HashMap::from([((0, 3), Color::rgb(0.5, 0.2, 0.1)), ((0, 4), Color::rgb(0.5, 0.2, 0.1))]);
+    first.ceilings = HashMap::from([((0, 3), Color::rgb(0.1, 0.1, 0.3)), ((0, 4), Color::rgb(0.1, 0.1, 0.3))]);
+    first.floor_materials = HashMap::from([((0, 3), Material::Carpet), ((0, 4), Material::Carpet)]);
+    first.brightness = HashMap::from([((-3, -2), 0.6), ((-3, -1), 0.8)]);
+    first.face_brightness = HashMap::from([(((1, 3), Normal::West), 1.4)]);
+    first.wall_colors = HashMap::from([((0, 5), Color::rgb(1., 0.35, 0.05))]);
+    first.fog_density = HashMap::from([((0, 3), 0.6), ((0, 4), 0.6)]);
+
+    let mut second = Environment::new([(-3,-3), (-2,-3), (-1,-3), (0,-3), (1,-3), (2,-3), (3,-3), (3,-2), (3,-1), (3,0), (3,1), (3,2), (3,3), (2,3), (1,3), (0,3), (-1,3), (-2,3), (-3,3), (-3,2), (-3,1), (-3,0), (-3,-1), (-3,-2), (1, 1), (-2, -1), (1, -1)]);
+    second.kinds = HashMap::from([((-2, -1), WallKind::Glass)]);
+    second.heights = HashMap::from([((1, 1), 2.)]);
+    second.floors = HashMap::from([((0, -1), Color::rgb(0.15, 0.25, 0.15))]);
+    second.floor_depths = HashMap::from([((0, -1), 0.5)]);
+    second.one_sided = HashMap::from([((1, -1), Normal::West)]);
+
+    let mut levels = Levels {
+        levels: vec![
+            Level {
+                environment: first,
+                spawn_position: Vec2::new(0.0, 0.0),
+                spawn_rotation: 0.0,
+                border: None,
+                items: vec![((-2, 0), ItemKind::Ammo), ((1, -2), ItemKind::Health)],
+                sky: None,
+                ambient: None,
+            },
+            Level {
+                environment: second,
+                spawn_position: Vec2::new(-1.5, 1.5),
+                spawn_rotation: -PI / 4.,
+                border: None,
+                items: vec![((2, 2), ItemKind::Key)],
+                sky: Some(vec![
+                    Color::rgb(0.35, 0.55, 0.85), Color::rgb(0.4, 0.6, 0.9), Color::rgb(0.5, 0.7, 0.95), Color::rgb(1., 0.9, 0.6),
+                    Color::rgb(0.5, 0.7, 0.95), Color::rgb(0.4, 0.6, 0.9), Color::rgb(0.35, 0.55, 0.85), Color::rgb(0.3, 0.5, 0.8),
+                ]),
+                ambient: Some(0.15),
+            },
+        ],
+        active: 0,
+        preserve_player: false,
+    };
+    for level in &mut levels.levels {
+        if let Some(kind) = level.border {
+            level.environment.add_border(kind);
+        }
+    }
+    levels
+}
+
+#[derive(Component)]
+struct Player {}
+
+// the second player of RenderConfig::split_screen, walks with its own keys and can't use the mouse or weapon
+#[derive(Component)]
+struct SecondPlayer {}
+
+// an entity the first-person view is rendered from, into the `slot`th of the side by side views
+#[derive(Component)]
+struct Viewer {
+    slot: usize,
+}
+
+#[derive(Component, Clone, Copy, Serialize)]
+struct Pose {
+    position: Vec2,
+    rotation: f32,
+}
+
+// how far the view is tilted around the screen center, in radians
+#[derive(Component)]
+struct CameraRoll {
+    angle: f32,
+}
+
+// how high the player's feet are above the floor, raised while standing on a low wall
+#[derive(Component)]
+struct Elevation {
+    height: f32,
+}
+
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
+struct MovementConfig {
+    speed: f32,
+    // radians per second
+    turning_speed: f32,
+    // radians per pixel of mouse movement while the cursor is captured
+    mouse_sensitivity: f32,
+    // lets the player walk onto walls no taller than `step_height`
+    step_climbing: bool,
+    step_height: f32,
+    // stops the player from walking through entities marked Blocking
+    entity_collision: bool,
+    mode: MovementMode,
+    // seconds a GridLocked step to the next cell or quarter turn takes
+    step_duration: f32,
+    // eases the view toward a target while the player isn't turning
+    aim_assist: AimAssist,
+    // fraction of the remaining angle closed per second
+    aim_assist_strength: f32,
+    // radius of the arc wall corners that stick out into open space are rounded off to, so sliding along a wall
+    // carries the player around its end instead of snagging; 0 keeps the corners square
+    corner_radius: f32,
+    // pixels of mouse movement per frame below which the frame's motion is treated as sensor noise and dropped
+    look_dead_zone: f32,
+    // length of the combined movement input below which the player stands still; keys always give 0 or at least 1
+    move_dead_zone: f32,
+}
+
+impl Default for MovementConfig {
+    fn default() -> Self {
+        MovementConfig {
+            speed: 3.,
+            turning_speed: PI,
+            mouse_sensitivity: 0.003,
+            step_climbing: false,
+            step_height: 0.3,
+            entity_collision: true,
+            corner_radius: 0.,
+            mode: MovementMode::Free,
+            step_duration: 0.25,
+            aim_assist: AimAssist::Off,
+            aim_assist_strength: 3.,
+            look_dead_zone: 0.,
+            move_dead_zone: 0.,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum AimAssist {
+    Off,
+    // the nearest of the four axis directions
+    Cardinal,
+    // the enemy in view closest to where the player is looking, nothing when none can be seen
+    Enemy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum MovementMode {
+    // walk and turn smoothly in any direction
+    Free,
+    // dungeon crawler style, move one cell at a time and turn in quarter turns
+    GridLocked,
+}
+
+#[derive(Component)]
+struct WallKindLabel {}
+
+#[derive(Component)]
+struct Compass {}
+
+#[derive(Component)]
+struct AsciiView {}
+
+// fires TriggerEntered each time the player steps into any of the cells from min to max (inclusive)
+#[derive(Component)]
+struct TriggerRegion {
+    // index into Levels, regions of inactive levels never fire
+    level: usize,
+    min: IVec2,
+    max: IVec2,
+}
+
+#[derive(Event)]
+struct TriggerEntered(Entity);
+
+#[derive(Resource)]
+struct WeaponConfig {
+    // where shots start relative to the eye, x forward and y to the left
+    muzzle_offset: Vec2,
+    damage: f32,
+    // world units per second of the projectile a shot fires, None hits instantly along the muzzle ray
+    projectile_speed: Option<f32>,
+}
+
+impl Default for WeaponConfig {
+    fn default() -> Self {
+        WeaponConfig {
+            muzzle_offset: Vec2::ZERO,
+            damage: 1.,
+            projectile_speed: None,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct CameraRollConfig {
+    enabled: bool,
+    // largest tilt in radians
+    max_angle: f32,
+    // radians of tilt per unit of sideways speed
+    per_speed: f32,
+    // how quickly the tilt follows its target, higher is snappier
+    smoothing: f32,
+}
+
+impl Default for CameraRollConfig {
+    fn default() -> Self {
+        CameraRollConfig {
+            enabled: false,
+            max_angle: 3f32.to_radians(),
+            per_speed: 1f32.to_radians(),
+            smoothing: 8.,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct CrosshairConfig {
+    color: Color,
+    // length of each arm and the empty space around the center, in pixels
+    size: f32,
+    gap: f32,
+    hit_color: Color,
+    // how long the hit marker stays up after a shot connects, in seconds
+    hit_duration: f32,
+    // how far the hit marker arms travel outward while it fades
+    hit_expand: f32,
+}
+
+impl Default for CrosshairConfig {
+    fn default() -> Self {
+        CrosshairConfig {
+            color: Color::rgba(1., 1., 1., 0.8),
+            size: 8.,
+            gap: 4.,
+            hit_color: Color::rgb(1., 0.3, 0.2),
+            hit_duration: 0.25,
+            hit_expand: 6.,
+        }
+    }
+}
+
+#[derive(Event)]
+struct ShotFired;
+
+// a shot hit this wall cell instead of an enemy
+#[derive(Event)]
+struct WallImpact {
+    cell: (i32, i32),
+}
+
+// sent when a door starts to open or close
+#[derive(Event)]
+struct DoorMoved {
+    opening: bool,
+}
+
+#[derive(Resource)]
+struct AudioConfig {
+    // scales every other volume
+    master_volume: f32,
+    music_volume: f32,
+    fire_volume: f32,
+    door_volume: f32,
+    damage_volume: f32,
+    impact_volume: f32,
+    footstep_volume: f32,
+    pickup_volume: f32,
+    // world units walked between two footsteps
+    footstep_stride: f32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            master_volume: 1.,
+            music_volume: 0.4,
+            fire_volume: 0.8,
+            door_volume: 0.6,
+            damage_volume: 0.8,
+            impact_volume: 0.5,
+            footstep_volume: 0.3,
+            pickup_volume: 0.6,
+            footstep_stride: 0.8,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct SoundEffects {
+    fire: Handle<AudioSource>,
+    door_open: Handle<AudioSource>,
+    door_close: Handle<AudioSource>,
+    damage: Handle<AudioSource>,
+    pickup: Handle<AudioSource>,
+    // for walls and floors without a material, the maps below have one sound per Material
+    impact: Handle<AudioSource>,
+    footstep: Handle<AudioSource>,
+    impacts: HashMap<Material, Handle<AudioSource>>,
+    footsteps: HashMap<Material, Handle<AudioSource>>,
+}
+
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
+struct MinimapConfig {
+    enabled: bool,
+    corner: ScreenCorner,
+    // side length of the square minimap and its distance from the window edges, in pixels
+    size: f32,
+    margin: f32,
+    zoom: MinimapZoom,
+    background: Color,
+    border: Color,
+    walls: Color,
+    player: Color,
+    enemies: Color,
+    // outline around the cell the player stands in, None leaves it out
+    player_cell: Option<Color>,
+}
+
+impl Default for MinimapConfig {
+    fn default() -> Self {
+        MinimapConfig {
+            enabled: false,
+            corner: ScreenCorner::TopRight,
+            size: 160.,
+            margin: 10.,
+            zoom: MinimapZoom::Fit,
+            background: Color::rgba(0., 0., 0., 0.8),
+            border: Color::GRAY,
+            walls: Color::WHITE,
+            player: Color::CYAN,
+            enemies: Color::RED,
+            player_cell: None,
+        }
+    }
+}
+
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
+struct CompassConfig {
+    enabled: bool,
+    corner: ScreenCorner,
+    // distance from the window edges, in pixels
+    margin: f32,
+    font_size: f32,
+    color: Color,
+}
+
+impl Default for CompassConfig {
+    fn default() -> Self {
+        CompassConfig {
+            enabled: false,
+            corner: ScreenCorner::BottomLeft,
+            margin: 10.,
+            font_size: 20.,
+            color: Color::WHITE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum ScreenCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum MinimapZoom {
+    // the whole level's bounds fill the minimap
+    Fit,
+    // centered on the player, showing this many cells across
+    Follow { cells: f32 },
+}
+
+// whether the cursor is grabbed for mouse look
+#[derive(Resource, Default)]
+struct CursorCapture {
+    captured: bool,
+}
+
+// multiplies the frame delta seen by simulation systems, rendering and input keep running in real time
+#[derive(Resource)]
+struct TimeScale {
+    scale: f32,
+    // factor applied per slow down / speed up key press
+    step: f32,
+    min: f32,
+    max: f32,
+}
+
+impl Default for TimeScale {
+    fn default() -> Self {
+        TimeScale {
+            scale: 1.,
+            step: 2.,
+            min: 1. / 16.,
+            max: 4.,
+        }
+    }
+}
+
+impl TimeScale {
+    fn delta_seconds(&self, time: &Time) -> f32 {
+        time.delta_seconds() * self.scale
+    }
+}
+
+// follows the distance walked rather than time, so it settles when the player stands still
+#[derive(Resource, Default)]
+struct HeadBob {
+    walked: f32,
+    // eases toward the walking speed as a fraction of full speed, scales the bob
+    intensity: f32,
+    // how far the horizon is raised, as a fraction of the view height
+    offset: f32,
+}
+
+// how far the first player walked this frame, worked out once by track_walked for the head bob and footsteps
+#[derive(Resource, Default)]
+struct Walked {
+    last_position: Option<Vec2>,
+    distance: f32,
+}
+
+impl HeadBob {
+    // the bob follows the first player's steps, the other views stay level
+    fn offset_for(&self, slot: usize) -> f32 {
+        if slot == 0 { self.offset } else { 0. }
+    }
+}
+
+// while set, draw_scene casts its rays from this pose instead of the player's, so the player can walk around
+// a fixed set of rays in the map view
+#[derive(Resource, Default)]
+struct FrozenView {
+    pose: Option<Pose>,
+}
+
+// a scripted flythrough for demos, loaded from CAMERA_PATH_PATH each time it starts; while it plays it sets the
+// player's pose last thing every frame, so movement and look input have no effect
+#[derive(Resource, Default)]
+struct CameraPath {
+    keyframes: Vec<Keyframe>,
+    // seconds since the path started, None when it isn't playing
+    elapsed: Option<f32>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+struct Keyframe {
+    // seconds from the start of the path, keyframes must be in order
+    time: f32,
+    position: Vec2,
+    rotation: f32,
+}
+
+// raycast stats summed since the last log line, logged about once a second while enabled
+#[derive(Resource, Default)]
+struct RaycastStatsLog {
+    enabled: bool,
+    totals: RaycastStats,
+    frames: u32,
+    elapsed: f32,
+}
+
+// the step or turn a GridLocked player is in the middle of, positions and rotations it goes between
+#[derive(Resource, Default)]
+struct GridStep {
+    tween: Option<(Vec2, f32, Vec2, f32)>,
+    elapsed: f32,
+}
+
+// the screen column whose ray is inspected when DEBUG_SELECTED_RAY is on, None until one is picked
+#[derive(Resource, Default)]
+struct SelectedRay {
+    column: Option<u32>,
+}
+
+#[derive(Resource, Default)]
+struct RngConfig {
+    // everything random in a run comes from GameRng, so the same seed replays the same game
+    seed: u64,
+}
+
+#[derive(Resource)]
+struct GameRng(StdRng);
+
+struct Level {
+    environment: Environment,
+    spawn_position: Vec2,
+    spawn_rotation: f32,
+    // wraps the map in a wall of this kind when it's loaded, for maps that are open to the outside
+    border: Option<WallKind>,
+    // items still lying around, one per cell; picked up ones are removed so they don't come back on a revisit
+    items: Vec<((i32, i32), ItemKind)>,
+    // a panorama wrapped once around the horizon, drawn above the walls in place of the ceiling and scrolling
+    // as the player turns; None keeps the flat ceiling
+    sky: Option<Vec<Color>>,
+    // replaces RenderConfig::ambient while this level is active
+    ambient: Option<f32>,
+}
+
+#[derive(Resource)]
+struct Levels {
+    levels: Vec<Level>,
+    active: usize,
+    // keep the player where they are on a switch instead of moving them to the new level's spawn
+    preserve_player: bool,
+}
+
+impl Levels {
+    fn active(&self) -> &Environment {
+        &self.levels[self.active].environment
+    }
+
+    fn active_mut(&mut self) -> &mut Environment {
+        &mut self.levels[self.active].environment
+    }
+
+    fn active_level(&self) -> &Level {
+        &self.levels[self.active]
+    }
+}
+
+// switches the active level, the index wraps around the loaded levels
+#[derive(Event)]
+struct SwitchLevel(usize);
+
+#[derive(Resource)]
+struct ScreenShake {
+    enabled: bool,
+    // current strength in pixels, decays back to zero
+    magnitude: f32,
+    max_magnitude: f32,
+    // fraction of the magnitude lost per second
+    decay: f32,
+    fire_kick: f32,
+    damage_kick: f32,
+    // where the view is pushed this frame
+    offset: Vec2,
+}
+
+impl Default for ScreenShake {
+    fn default() -> Self {
+        ScreenShake {
+            enabled: true,
+            magnitude: 0.,
+            max_magnitude: 20.,
+            decay: 8.,
+            fire_kick: 4.,
+            damage_kick: 12.,
+            offset: Vec2::ZERO,
+        }
+    }
+}
+
+impl ScreenShake {
+    fn kick(&mut self, amount: f32) {
+        if !self.enabled { return }
+        self.magnitude = (self.magnitude + amount).min(self.max_magnitude);
+    }
+}
+
+// the part of the window the first-person view is rendered into, `width` by `height` render pixels
+// around the window center with each one covering `scale` window pixels
+#[derive(Clone, Copy)]
+struct Viewport {
+    width: f32,
+    height: f32,
+    scale: Vec2,
+    // window pixels from the window center to the view's center
+    center: Vec2,
+    // round strip ends to whole render pixels so upscaled pixels stay square
+    pixel_snap: bool,
+}
+
+impl Viewport {
+    // the view for `slot`, with RenderConfig::split_screen the window is shared by two side by side, slot 0 on the left
+    fn new(window: &Window, render_config: &RenderConfig, slot: usize) -> Self {
+        let slots = if render_config.split_screen { 2. } else { 1. };
+        let window_size = Vec2::new(window.resolution.width() / slots, window.resolution.height());
+        let center = Vec2::new((slot as f32 + 0.5) * window_size.x - window.resolution.width() / 2., 0.);
+
+        let Some(internal) = render_config.internal_resolution else {
+            return Viewport { width: window_size.x, height: window_size.y, scale: Vec2::ONE, center, pixel_snap: false };
+        };
+
+        let internal = internal.as_vec2();
+        let fit = (window_size / internal).min_element();
+        let scale = match render_config.scaling {
+            // a window smaller than the internal resolution shrinks the image to fit rather than cutting it off
+            Scaling::Integer if fit >= 1. => Vec2::splat(fit.floor()),
+            Scaling::Integer => Vec2::splat(fit),
+            Scaling::Stretch => window_size / internal,
+        };
+
+        Viewport { width: internal.x, height: internal.y, scale, center, pixel_snap: true }
+    }
+
+    // window pixel column to render column, columns count from the right edge like everywhere else
+    fn column_at(&self, window_x: f32, window_width: f32) -> f32 {
+        self.width / 2. - (window_x - window_width / 2. - self.center.x) / self.scale.x
+    }
+
+    // render pixels around the view's center to window pixels around the window's center
+    fn window_point(&self, point: Vec2) -> Vec2 {
+        point * self.scale + self.center
+    }
+}
+
+// screen space transform applied to everything drawn in the first-person view
+struct ViewTransform {
+    rotation: Vec2,
+    offset: Vec2,
+    // how far the horizon is raised, in render pixels
+    horizon: f32,
+    viewport: Viewport,
+    // RenderConfig::palette, empty when colors are drawn as they are
+    palette: Vec<Color>,
+    dither: bool,
+}
+
+impl ViewTransform {
+    // `bob` is HeadBob::offset_for the view
+    fn new(roll: &CameraRoll, screen_shake: &ScreenShake, bob: f32, viewport: Viewport, render_config: &RenderConfig) -> Self {
+        ViewTransform {
+            rotation: Vec2::from_angle(roll.angle),
+            offset: screen_shake.offset,
+            horizon: bob * viewport.height,
+            viewport,
+            palette: render_config.palette.clone().unwrap_or_default(),
+            dither: render_config.palette_dither,
+        }
+    }
+
+    // the palette color closest to `color`, `color` itself without a palette
+    fn quantize(&self, color: Color) -> Color {
+        nearest_palette_colors(&self.palette, color).map_or(color, |(nearest, _, _)| nearest)
+    }
+
+    // render pixels to window pixels
+    fn apply(&self, point: Vec2) -> Vec2 {
+        self.rotation.rotate((point + Vec2::new(0., self.horizon)) * self.viewport.scale) + self.offset + self.viewport.center
+    }
+
+    // a vertical strip one render pixel wide, upscaled columns are filled with one line per window pixel
+    fn column_line(&self, gizmos: &mut Gizmos, x: f32, bottom: f32, top: f32, color: Color) {
+        let (bottom, top) = if self.viewport.pixel_snap { (bottom.round(), top.round()) } else { (bottom, top) };
+        if top <= bottom { return }
+
+        let lines = self.viewport.scale.x.ceil().max(1.) as u32;
+        for (bottom, top, color) in self.palette_runs(x, bottom, top, color) {
+            for line in 0..lines {
+                let x = x + (line as f32 + 0.5) / lines as f32 - 0.5;
+                gizmos.line_2d(self.apply(Vec2::new(x, bottom)), self.apply(Vec2::new(x, top)), color);
+            }
+        }
+    }
+
+    // the strip from `bottom` to `top` split into runs of palette colors. without dithering the whole strip gets
+    // the nearest one, with it every pixel picks between the two nearest by the ordered dither pattern, so on
+    // average the strip comes out at `color`
+    fn palette_runs(&self, x: f32, bottom: f32, top: f32, color: Color) -> Vec<(f32, f32, Color)> {
+        let Some((nearest, second, blend)) = nearest_palette_colors(&self.palette, color) else { return vec![(bottom, top, color)] };
+        if !self.dither || blend <= 0. { return vec![(bottom, top, nearest)] }
+
+        let column = x.floor() as i32;
+        let mut runs: Vec<(f32, f32, Color)> = Vec::new();
+
+        for row in bottom.floor() as i32..top.ceil() as i32 {
+            let threshold = (SCREEN_DOOR_PATTERN[column.rem_euclid(4) as usize][row.rem_euclid(4) as usize] + 0.5) / 16.;
+            let pick = if threshold < blend { second } else { nearest };
+
+            let (from, to) = ((row as f32).max(bottom), (row as f32 + 1.).min(top));
+            match runs.last_mut() {
+                Some(run) if run.1 == from && run.2 == pick => run.1 = to,
+                _ => runs.push((from, to, pick)),
+            }
+        }
+
+        runs
+    }
+}
+
+#[derive(Component)]
+struct Enemy {
+    position: Vec2,
+    // the way the enemy is looking, in radians like Pose::rotation
+    facing: f32,
+}
+
+// something lying in a cell, picked up by walking into that cell
+#[derive(Component)]
+struct Item {
+    kind: ItemKind,
+    cell: (i32, i32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ItemKind {
+    Ammo,
+    Health,
+    Key,
+}
+
+impl ItemKind {
+    // no item sprites yet, so each kind is a colored block
+    fn color(self) -> Color {
+        match self {
+            ItemKind::Ammo => Color::GOLD,
+            ItemKind::Health => Color::LIME_GREEN,
+            ItemKind::Key => Color::ALICE_BLUE,
+        }
+    }
+}
+
+// how many of each item the player has picked up
+#[derive(Resource, Default)]
+struct Inventory {
+    counts: HashMap<ItemKind, u32>,
+}
+
+#[derive(Event)]
+struct ItemPickedUp;
+
+// entities the player can't walk through, as a circle of `radius` around the entity's position
+#[derive(Component)]
+struct Blocking {
+    radius: f32,
+}
+
+#[derive(Component)]
+struct Health {
+    hp: f32,
+}
+
+#[derive(Event)]
+struct DamageEvent {
+    target: Entity,
+    amount: f32,
+}
+
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
+struct EnemyConfig {
+    count: usize,
+    // cells to spawn in on every level, skipping any that are walls there or hold the player; None spreads the
+    // enemies over the open cells reachable from the player
+    spawn_cells: Option<Vec<(i32, i32)>>,
+    health: f32,
+    radius: f32,
+    speed: f32,
+    // how close a chasing enemy gets before it stops
+    chase_distance: f32,
+}
+
+impl Default for EnemyConfig {
+    fn default() -> Self {
+        EnemyConfig {
+            count: 3,
+            spawn_cells: None,
+            health: 3.,
+            radius: 0.3,
+            speed: 1.5,
+            chase_distance: 1.,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct DoorConfig {
+    // auto doors open while the player is closer than this to the door cell's center
+    open_distance: f32,
+    // openness gained or lost per second
+    speed: f32,
+}
+
+impl Default for DoorConfig {
+    fn default() -> Self {
+        DoorConfig {
+            open_distance: 1.5,
+            speed: 2.,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct DebugColors {
+    axes: Color,
+    player_front: Color,
+    player_left: Color,
+    player_right: Color,
+    walls: Color,
+    doors: Color,
+    glass: Color,
+    windows: Color,
+    fog_walls: Color,
+    emissive_walls: Color,
+    ray_hit: Color,
+    ray_miss: Color,
+    xray_hit: Color,
+    visible_walls: Color,
+    selected_ray: Color,
+    visibility_polygon: Color,
+    frozen_view: Color,
+    player_cell: Color,
+    enemies: Color,
+}
+
+impl Default for DebugColors {
+    fn default() -> Self {
+        DebugColors {
+            axes: Color::GRAY,
+            player_front: Color::BLUE,
+            player_left: Color::RED,
+            player_right: Color::GREEN,
+            walls: Color::WHITE,
+            doors: Color::ORANGE,
+            glass: Color::TEAL,
+            windows: Color::SEA_GREEN,
+            fog_walls: Color::PURPLE,
+            emissive_walls: Color::ORANGE,
+            ray_hit: Color::GREEN,
+            ray_miss: Color::RED,
+            xray_hit: Color::YELLOW,
+            visible_walls: Color::CYAN,
+            selected_ray: Color::FUCHSIA,
+            visibility_polygon: Color::YELLOW_GREEN,
+            frozen_view: Color::ORANGE_RED,
+            player_cell: Color::GOLD,
+            enemies: Color::RED,
+        }
+    }
+}
+
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
+struct RenderConfig {
+    // horizontal field of view in radians
+    fov: f32,
+    // cast one ray per `column_stride` screen columns and stretch it across them
+    column_stride: u32,
+    // cast only this many rays spread across the view and fill in the columns between them, ignores column_stride;
+    // None casts every column_stride columns
+    ray_count: Option<u32>,
+    // output colors are raised to 1 / gamma, so values above 1 brighten the image
+    gamma: f32,
+    projection: ProjectionMode,
+    // world units covered by the screen width in orthographic mode
+    orthographic_width: f32,
+    anchor: WallAnchor,
+    camera_model: CameraModel,
+    // eye height above the floor as a fraction of a 1-tall wall, used by FloorAligned
+    eye_height: f32,
+    // how much saturation is lost by `desaturation_distance`, 0 keeps colors as they are and 1 fades them to grey
+    depth_desaturation: f32,
+    desaturation_distance: f32,
+    draw_floors: bool,
+    // for cells without their own floor or ceiling color
+    floor_color: Color,
+    ceiling_color: Color,
+    // how WallKind::Glass walls are drawn
+    glass: RenderMode,
+    // render the first-person view at this size and scale it up to the window, None renders at window size
+    internal_resolution: Option<UVec2>,
+    scaling: Scaling,
+    // bob the view up and down while walking
+    headbob: bool,
+    // as a fraction of the view height, reached when walking at full speed
+    headbob_amplitude: f32,
+    // bobs per world unit walked
+    headbob_frequency: f32,
+    vsync: bool,
+    // frames per second to stay under even without vsync, None to run as fast as possible for benchmarking
+    max_fps: Option<f32>,
+    // brightens walls inside a cone in front of the player, fading out towards the cone's edge and its range
+    flashlight: bool,
+    // full width of the cone in radians
+    flashlight_angle: f32,
+    flashlight_range: f32,
+    // lightness added at the center of the cone right in front of the player
+    flashlight_intensity: f32,
+    // lowest lightness distance falloff can take walls, floors and ceilings down to, for levels that don't set their own
+    ambient: f32,
+    // what the window shows, cycled with KeyBindings::cycle_view_mode
+    view_mode: ViewMode,
+    // distance ViewMode::Depth colors red
+    depth_view_range: f32,
+    // what the fog of Environment::fog_density fades walls, floors and ceilings toward
+    fog_color: Color,
+    // spawn a second player and split the window between their two views, read once at startup
+    split_screen: bool,
+    // quantize everything drawn in the first-person view to these colors, None draws colors as they are
+    palette: Option<Vec<Color>>,
+    // mix the two nearest palette colors with the ordered dither pattern instead of taking the nearest
+    palette_dither: bool,
+}
+
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
+struct KeyBindings {
+    forward: KeyCode,
+    back: KeyCode,
+    strafe_left: KeyCode,
+    strafe_right: KeyCode,
+    turn_left: KeyCode,
+    turn_right: KeyCode,
+    fire: KeyCode,
+    toggle_projection: KeyCode,
+    toggle_anchor: KeyCode,
+    toggle_camera_model: KeyCode,
+    toggle_camera_roll: KeyCode,
+    toggle_screen_shake: KeyCode,
+    next_level: KeyCode,
+    print_view: KeyCode,
+    dump_state: KeyCode,
+    release_cursor: KeyCode,
+    slow_down: KeyCode,
+    speed_up: KeyCode,
+    reset_time_scale: KeyCode,
+    select_ray_left: KeyCode,
+    select_ray_right: KeyCode,
+    toggle_minimap: KeyCode,
+    toggle_headbob: KeyCode,
+    coverage_report: KeyCode,
+    toggle_compass: KeyCode,
+    toggle_flashlight: KeyCode,
+    raycast_stats: KeyCode,
+    freeze_view: KeyCode,
+    play_camera_path: KeyCode,
+    // moves the player to the cell under the cursor in ViewMode::TopDown, so does a middle click
+    teleport: KeyCode,
+    cycle_view_mode: KeyCode,
+    // movement of the split screen second player
+    second_forward: KeyCode,
+    second_back: KeyCode,
+    second_strafe_left: KeyCode,
+    second_strafe_right: KeyCode,
+    second_turn_left: KeyCode,
+    second_turn_right: KeyCode,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            forward: KeyCode::KeyW,
+            back: KeyCode::KeyS,
+            strafe_left: KeyCode::KeyA,
+            strafe_right: KeyCode::KeyD,
+            turn_left: KeyCode::ArrowLeft,
+            turn_right: KeyCode::ArrowRight,
+            fire: KeyCode::Space,
+            toggle_projection: KeyCode::KeyO,
+            toggle_anchor: KeyCode::KeyG,
+            toggle_camera_model: KeyCode::KeyC,
+            toggle_camera_roll: KeyCode::KeyR,
+            toggle_screen_shake: KeyCode::KeyK,
+            next_level: KeyCode::KeyN,
+            print_view: KeyCode::KeyP,
+            dump_state: KeyCode::F5,
+            release_cursor: KeyCode::Escape,
+            slow_down: KeyCode::BracketLeft,
+            speed_up: KeyCode::BracketRight,
+            reset_time_scale: KeyCode::Backslash,
+            select_ray_left: KeyCode::Comma,
+            select_ray_right: KeyCode::Period,
+            toggle_minimap: KeyCode::KeyM,
+            toggle_headbob: KeyCode::KeyB,
+            coverage_report: KeyCode::F6,
+            toggle_compass: KeyCode::KeyH,
+            toggle_flashlight: KeyCode::KeyF,
+            raycast_stats: KeyCode::F7,
+            freeze_view: KeyCode::F8,
+            play_camera_path: KeyCode::F9,
+            teleport: KeyCode::KeyT,
+            cycle_view_mode: KeyCode::KeyV,
+            second_forward: KeyCode::Numpad8,
+            second_back: KeyCode::Numpad5,
+            second_strafe_left: KeyCode::Numpad4,
+            second_strafe_right: KeyCode::Numpad6,
+            second_turn_left: KeyCode::Numpad7,
+            second_turn_right: KeyCode::Numpad9,
+        }
+    }
+}
+
+impl KeyBindings {
+    fn walk_keys(&self) -> WalkKeys {
+        WalkKeys {
+            forward: self.forward,
+            back: self.back,
+            strafe_left: self.strafe_left,
+            strafe_right: self.strafe_right,
+            turn_left: self.turn_left,
+            turn_right: self.turn_right,
+        }
+    }
+
+    fn second_walk_keys(&self) -> WalkKeys {
+        WalkKeys {
+            forward: self.second_forward,
+            back: self.second_back,
+            strafe_left: self.second_strafe_left,
+            strafe_right: self.second_strafe_right,
+            turn_left: self.second_turn_left,
+            turn_right: self.second_turn_right,
+        }
+    }
+}
+
+// the keys one player walks with
+#[derive(Clone, Copy)]
+struct WalkKeys {
+    forward: KeyCode,
+    back: KeyCode,
+    strafe_left: KeyCode,
+    strafe_right: KeyCode,
+    turn_left: KeyCode,
+    turn_right: KeyCode,
+}
+
+// everything that can be set from the config file, missing fields keep their defaults
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    render: RenderConfig,
+    movement: MovementConfig,
+    key_bindings: KeyBindings,
+    minimap: MinimapConfig,
+    compass: CompassConfig,
+    enemies: EnemyConfig,
+}
+
+impl Config {
+    // puts values that would break rendering or movement back to their defaults
+    fn validated(mut self) -> Self {
+        let render_defaults = RenderConfig::default();
+        let movement_defaults = MovementConfig::default();
+        let enemy_defaults = EnemyConfig::default();
+
+        if !(self.render.fov > 0. && self.render.fov < PI) {
+            warn!("fov must be between 0 and pi, got {}", self.render.fov);
+            self.render.fov = render_defaults.fov;
+        }
+        if self.render.column_stride == 0 {
+            warn!("column_stride must be at least 1");
+            self.render.column_stride = render_defaults.column_stride;
+        }
+        if self.render.ray_count.is_some_and(|count| count < 2) {
+            warn!("ray_count must be at least 2, leave it out to cast every column");
+            self.render.ray_count = render_defaults.ray_count;
+        }
+        if self.render.gamma <= 0. {
+            warn!("gamma must be positive, got {}", self.render.gamma);
+            self.render.gamma = render_defaults.gamma;
+        }
+        if self.render.orthographic_width <= 0. {
+            warn!("orthographic_width must be positive, got {}", self.render.orthographic_width);
+            self.render.orthographic_width = render_defaults.orthographic_width;
+        }
+        if !(0. ..=1.).contains(&self.render.eye_height) {
+            warn!("eye_height must be between 0 and 1, got {}", self.render.eye_height);
+            self.render.eye_height = render_defaults.eye_height;
+        }
+        if self.render.desaturation_distance <= 0. {
+            warn!("desaturation_distance must be positive, got {}", self.render.desaturation_distance);
+            self.render.desaturation_distance = render_defaults.desaturation_distance;
+        }
+        if !(self.render.flashlight_angle > 0. && self.render.flashlight_angle <= TAU) {
+            warn!("flashlight_angle must be between 0 and tau, got {}", self.render.flashlight_angle);
+            self.render.flashlight_angle = render_defaults.flashlight_angle;
+        }
+        if self.render.flashlight_range <= 0. {
+            warn!("flashlight_range must be positive, got {}", self.render.flashlight_range);
+            self.render.flashlight_range = render_defaults.flashlight_range;
+        }
+        if self.render.max_fps.is_some_and(|fps| fps <= 0.) {
+            warn!("max_fps must be positive, leave it out to uncap the frame rate");
+            self.render.max_fps = render_defaults.max_fps;
+        }
+        if self.render.internal_resolution.is_some_and(|size| size.x == 0 || size.y == 0) {
+            warn!("internal_resolution can't be zero");
+            self.render.internal_resolution = render_defaults.internal_resolution;
+        }
+        if let RenderMode::ScreenDoor { alpha } = self.render.glass {
+            if !(0. ..=1.).contains(&alpha) {
+                warn!("screen door alpha must be between 0 and 1, got {alpha}");
+                self.render.glass = render_defaults.glass;
+            }
+        }
+        if self.movement.speed < 0. {
+            warn!("speed can't be negative, got {}", self.movement.speed);
+            self.movement.speed = movement_defaults.speed;
+        }
+        if self.movement.turning_speed < 0. {
+            warn!("turning_speed can't be negative, got {}", self.movement.turning_speed);
+            self.movement.turning_speed = movement_defaults.turning_speed;
+        }
+        if self.render.palette.as_ref().is_some_and(|palette| palette.is_empty()) {
+            warn!("palette needs at least one color, drawing without one");
+            self.render.palette = None;
+        }
+        if self.render.depth_view_range <= 0. {
+            warn!("depth_view_range must be positive, got {}", self.render.depth_view_range);
+            self.render.depth_view_range = render_defaults.depth_view_range;
+        }
+        if !(0. ..=1.).contains(&self.render.ambient) {
+            warn!("ambient must be between 0 and 1, got {}", self.render.ambient);
+            self.render.ambient = render_defaults.ambient;
+        }
+        if !(0. ..=0.5).contains(&self.movement.corner_radius) {
+            warn!("corner_radius must be between 0 and 0.5, got {}", self.movement.corner_radius);
+            self.movement.corner_radius = movement_defaults.corner_radius;
+        }
+        if self.movement.look_dead_zone < 0. {
+            warn!("look_dead_zone can't be negative, got {}", self.movement.look_dead_zone);
+            self.movement.look_dead_zone = movement_defaults.look_dead_zone;
+        }
+        if self.movement.move_dead_zone < 0. {
+            warn!("move_dead_zone can't be negative, got {}", self.movement.move_dead_zone);
+            self.movement.move_dead_zone = movement_defaults.move_dead_zone;
+        }
+        if self.enemies.speed < 0. {
+            warn!("enemy speed can't be negative, got {}", self.enemies.speed);
+            self.enemies.speed = enemy_defaults.speed;
+        }
+        if self.enemies.radius < 0. {
+            warn!("enemy radius can't be negative, got {}", self.enemies.radius);
+            self.enemies.radius = enemy_defaults.radius;
+        }
+
+        self
+    }
+}
+
+// reads the config file, falling back to the defaults if it's missing or can't be parsed
+fn load_config(path: &Path) -> Config {
+    let config = match read_config_file(path) {
+        Some(contents) => ron::from_str(&contents).unwrap_or_else(|error| {
+            warn!("couldn't parse {}: {error}, using the default config", path.display());
+            Config::default()
+        }),
+        None => Config::default(),
+    };
+
+    config.validated()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_config_file(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+// there's no file system in the browser, so web builds always start from the defaults
+#[cfg(target_arch = "wasm32")]
+fn read_config_file(_path: &Path) -> Option<String> {
+    None
+}
+
+fn apply_config(mut commands: Commands) {
+    let config = load_config(Path::new(CONFIG_PATH));
+
+    commands.insert_resource(config.render);
+    commands.insert_resource(config.movement);
+    commands.insert_resource(config.key_bindings);
+    commands.insert_resource(config.minimap);
+    commands.insert_resource(config.compass);
+    commands.insert_resource(config.enemies);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum WallAnchor {
+    // walls grow and shrink around the horizon, so taller walls also sink below the floor
+    Centered,
+    // every wall stands on the floor line below the eye; needed for per-cell
+    // heights to look right and for anything that moves the eye up or down
+    FloorAligned,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum RenderMode {
+    Opaque,
+    // cheap transparency without blending: a fixed dither pattern skips about 1 - alpha of the strip's
+    // pixels so whatever is behind shows through the gaps
+    ScreenDoor { alpha: f32 },
+}
+
+// how an internal resolution is fitted to the window
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Scaling {
+    // the largest whole multiple that fits, centered with black bars around it, for crisp square pixels
+    Integer,
+    // fills the whole window, pixels end up uneven and not square
+    Stretch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum ProjectionMode {
+    Perspective,
+    // parallel rays offset sideways from the player, no fisheye correction
+    Orthographic,
+}
+
+impl RenderConfig {
+    fn top_down(&self) -> bool {
+        self.view_mode == ViewMode::TopDown
+    }
+
+    // the first-person view and the sprites in it are drawn
+    fn first_person(&self) -> bool {
+        matches!(self.view_mode, ViewMode::Shaded | ViewMode::Flat | ViewMode::Depth)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum ViewMode {
+    // the first-person view, walls fading with distance, baked and ambient light, flashlight and fog
+    Shaded,
+    // the first-person view with every wall evenly lit, no distance falloff or flashlight
+    Flat,
+    // the first-person view with walls colored by hit distance, see depth_color
+    Depth,
+    // the level from above in world space, with the rays, the player and debug overlays
+    TopDown,
+    // the first-person view as text in the top left corner, one character per cell of an 80 by 24 grid
+    Ascii,
+}
+
+impl ViewMode {
+    fn next(self) -> Self {
+        match self {
+            ViewMode::Shaded => ViewMode::Flat,
+            ViewMode::Flat => ViewMode::Depth,
+            ViewMode::Depth => ViewMode::TopDown,
+            ViewMode::TopDown => ViewMode::Ascii,
+            ViewMode::Ascii => ViewMode::Shaded,
+        }
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig {
+            fov: PI / 2.,
+            column_stride: 1,
+            ray_count: None,
+            gamma: 1.0,
+            projection: ProjectionMode::Perspective,
+            orthographic_width: 8.,
+            anchor: WallAnchor::Centered,
+            camera_model: CameraModel::PlanarColumns,
+            eye_height: 0.5,
+            depth_desaturation: 0.,
+            desaturation_distance: 10.,
+            draw_floors: true,
+            floor_color: Color::rgb(0.3, 0.3, 0.3),
+            ceiling_color: Color::rgb(0.15, 0.15, 0.15),
+            glass: RenderMode::ScreenDoor { alpha: 0.5 },
+            internal_resolution: None,
+            scaling: Scaling::Integer,
+            headbob: true,
+            headbob_amplitude: 0.01,
+            headbob_frequency: 0.6,
+            vsync: true,
+            max_fps: Some(240.),
+            flashlight: false,
+            flashlight_angle: PI / 6.,
+            flashlight_range: 6.,
+            flashlight_intensity: 0.6,
+            ambient: 0.,
+            view_mode: ViewMode::Shaded,
+            depth_view_range: RAYCAST_DEPTH as f32,
+            fog_color: Color::rgb(0.5, 0.5, 0.55),
+            split_screen: false,
+            palette: None,
+            palette_dither: false,
+        }
+    }
+}
+
+#[derive(Component, Default)]
+struct DepthBuffer {
+    // distance to the wall hit by each screen column, infinite where the ray missed
+    distances: Vec<f32>,
+}
+
+// the per-column angles of the first-person view and the trig on them that stays the same from frame to frame,
+// rebuilt only when the render width, fov, camera model or projection changes
+#[derive(Resource, Default)]
+struct ColumnTable {
+    built_for: Option<(u32, f32, CameraModel, ProjectionMode)>,
+    angles: Vec<f32>,
+    // each column's ray with the viewer looking along +x
+    directions: Vec<Vec2>,
+    // CameraModel::depth_factor of each column's angle
+    depth_factors: Vec<f32>,
+}
+
+impl ColumnTable {
+    fn update(&mut self, render_width: u32, render_config: &RenderConfig) {
+        let key = (render_width, render_config.fov, render_config.camera_model, render_config.projection);
+        if self.built_for == Some(key) { return }
+
+        let unrotated = Pose { position: Vec2::ZERO, rotation: 0. };
+        let columns = (0..render_width).map(|column| column_ray(&unrotated, column, render_width, render_config));
+        (self.directions, self.angles) = columns.map(|(_, direction, angle)| (direction, angle)).unzip();
+        self.depth_factors = self.angles.iter().map(|&angle| render_config.camera_model.depth_factor(angle)).collect();
+        self.built_for = Some(key);
+    }
+
+    // column_rays without the per-column trig, for the view the table was last updated for
+    fn rays(&self, viewer: &Pose, stride: usize, render_config: &RenderConfig) -> Vec<(u32, Vec2, Vec2, f32)> {
+        let render_width = self.angles.len() as u32;
+        let rotation = Vec2::from_angle(viewer.rotation);
+
+        (0..render_width).step_by(stride).map(|column| match render_config.projection {
+            ProjectionMode::Perspective => (column, viewer.position, self.directions[column as usize].rotate(rotation), self.angles[column as usize]),
+            ProjectionMode::Orthographic => {
+                let (origin, direction, angle) = column_ray(viewer, column, render_width, render_config);
+                (column, origin, direction, angle)
+            }
+        }).collect()
+    }
+
+    // CameraModel::projected_height for the ray through `column`
+    fn projected_height(&self, column: usize, distance: f32, focal_length: f32) -> f32 {
+        focal_length / (distance * self.depth_factors[column]).max(NEAR_CLIP)
+    }
+}
+
+#[derive(Component, Default)]
+struct WallKindBuffer {
+    // kind of wall hit by each screen column, None where the ray missed
+    kinds: Vec<Option<WallKind>>,
+}
+
+// a shot in flight at eye height, moved by step_projectiles until it hits a wall or an enemy
+#[derive(Component)]
+struct Projectile {
+    pos: Vec2,
+    vel: Vec2,
+    damage: f32,
+}
+
+#[derive(Component)]
+struct Particle {
+    pos: Vec2,
+    vel: Vec2,
+    life: f32,
+}
+
+#[derive(Event)]
+struct ParticleBurst {
+    position: Vec2,
+    count: u32,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_player(
+    mut player_query: Query<(&mut Pose, &mut Elevation, &mut CameraRoll), With<Player>>,
+    levels: Res<Levels>,
+    movement_config: Res<MovementConfig>,
+    roll_config: Res<CameraRollConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+    mut grid_step: ResMut<GridStep>,
+) {
+    let Ok((mut pose, mut elevation, mut roll)) = player_query.get_single_mut() else { return };
+    let environment = levels.active();
+    let delta_seconds = time_scale.delta_seconds(&time);
+
+    let start_pos = pose.position;
+
+    if movement_config.mode == MovementMode::GridLocked {
+        step_on_grid(&mut pose, &mut grid_step, environment, &movement_config, &key_bindings, &keyboard, delta_seconds);
+    }
+    else {
+        walk(&mut pose, environment, &movement_config, key_bindings.walk_keys(), &keyboard, delta_seconds);
+    }
+
+    // lean into the strafe, based on how far the player actually moved sideways
+    let target_roll = if roll_config.enabled && delta_seconds > 0. {
+        let left = Vec2::from_angle(pose.rotation + PI / 2.);
+        let strafe_speed = (pose.position - start_pos).dot(left) / delta_seconds;
+        (-strafe_speed * roll_config.per_speed).clamp(-roll_config.max_angle, roll_config.max_angle)
+    } else {
+        0.
+    };
+    roll.angle += (target_roll - roll.angle) * (roll_config.smoothing * delta_seconds).min(1.);
+
+    let standing_on = world_to_cell(pose.position);
+    elevation.height = if environment.blocks_movement_from(standing_on, pose.position) { environment.height(standing_on) } else { 0. };
+}
+
+// turns and moves the player in any direction from the held keys, sliding along walls
+fn walk(
+    pose: &mut Pose,
+    environment: &Environment,
+    movement_config: &MovementConfig,
+    keys: WalkKeys,
+    keyboard: &ButtonInput<KeyCode>,
+    delta_seconds: f32,
+) {
+    let mut direction = Vec2::ZERO;
+
+    if keyboard.pressed(keys.turn_left)  {
+        pose.rotation += movement_config.turning_speed * delta_seconds;
+    }
+    if keyboard.pressed(keys.turn_right)  {
+        pose.rotation -= movement_config.turning_speed * delta_seconds;
+    }
+
+    pose.rotation %= TAU;
+
+    if keyboard.pressed(keys.forward)  {
+        direction += Vec2::new(1.0, 0.0);
+    }
+    if keyboard.pressed(keys.strafe_left)  {
+        direction += Vec2::new(0.0, 1.0);
+    }
+    if keyboard.pressed(keys.back)  {
+        direction += Vec2::new(-1.0, 0.0);
+    }
+    if keyboard.pressed(keys.strafe_right)  {
+        direction += Vec2::new(0.0, -1.0);
+    }
+
+    // opposite keys cancel out, the squared length is kept away from zero so a tiny leftover can't normalize
+    // into a huge or NaN step
+    if direction.length_squared() > movement_config.move_dead_zone.powi(2).max(f32::EPSILON) {
+        direction = direction.normalize_or_zero();
+        let player_direction = Vec2::from_angle(pose.rotation);
+        let velocity = direction.rotate(player_direction) * movement_config.speed * delta_seconds;
+        pose.position = move_with_collision(environment, movement_config, pose.position, velocity);
+    }
+}
+
+// the split screen second player only walks freely, there's no grid mode, mouse look or camera roll for it
+fn update_second_player(
+    mut player_query: Query<(&mut Pose, &mut Elevation), With<SecondPlayer>>,
+    levels: Res<Levels>,
+    movement_config: Res<MovementConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+) {
+    let Ok((mut pose, mut elevation)) = player_query.get_single_mut() else { return };
+    let environment = levels.active();
+
+    walk(&mut pose, environment, &movement_config, key_bindings.second_walk_keys(), &keyboard, time_scale.delta_seconds(&time));
+
+    let standing_on = world_to_cell(pose.position);
+    elevation.height = if environment.blocks_movement_from(standing_on, pose.position) { environment.height(standing_on) } else { 0. };
+}
+
+// a held key starts a step to the middle of the neighbouring cell or a quarter turn, which eases in over
+// step_duration; keys are ignored until it's done. the first step also snaps the player onto the grid
+fn step_on_grid(
+    pose: &mut Pose,
+    grid_step: &mut GridStep,
+    environment: &Environment,
+    movement_config: &MovementConfig,
+    key_bindings: &KeyBindings,
+    keyboard: &ButtonInput<KeyCode>,
+    delta_seconds: f32,
+) {
+    if let Some((from_position, from_rotation, to_position, to_rotation)) = grid_step.tween {
+        grid_step.elapsed += delta_seconds;
+        let t = (grid_step.elapsed / movement_config.step_duration.max(f32::EPSILON)).min(1.);
+
+        pose.position = from_position.lerp(to_position, t);
+        pose.rotation = from_rotation + (to_rotation - from_rotation) * t;
+
+        if t >= 1. {
+            pose.position = to_position;
+            pose.rotation = to_rotation % TAU;
+            grid_step.tween = None;
+        }
+        return;
+    }
+
+    let quarter = PI / 2.;
+    let rotation = (pose.rotation / quarter).round() * quarter;
+    let cell = world_to_cell(pose.position);
+    let center = Vec2::new(cell.0 as f32, cell.1 as f32) + Vec2::splat(0.5);
+
+    let mut to_rotation = rotation;
+    let mut offset = Vec2::ZERO;
+
+    if keyboard.pressed(key_bindings.turn_left) {
+        to_rotation += quarter;
+    } else if keyboard.pressed(key_bindings.turn_right) {
+        to_rotation -= quarter;
+    } else if keyboard.pressed(key_bindings.forward) {
+        offset = Vec2::X;
+    } else if keyboard.pressed(key_bindings.back) {
+        offset = Vec2::NEG_X;
+    } else if keyboard.pressed(key_bindings.strafe_left) {
+        offset = Vec2::Y;
+    } else if keyboard.pressed(key_bindings.strafe_right) {
+        offset = Vec2::NEG_Y;
+    } else if pose.position.distance(center) < 1e-4 && (pose.rotation - rotation).abs() < 1e-4 {
+        // already on the grid, nothing to do
+        return;
+    }
+
+    // rounded so the step lands exactly on the next cell's center
+    let step = offset.rotate(Vec2::from_angle(rotation)).round();
+    let target = (cell.0 + step.x as i32, cell.1 + step.y as i32);
+    let to_position = if step != Vec2::ZERO && !environment.blocks_movement_from(target, center) { center + step } else { center };
+
+    grid_step.tween = Some((pose.position, pose.rotation, to_position, to_rotation));
+    grid_step.elapsed = 0.;
+}
+
+#[allow(clippy::too_many_arguments)]
+fn assist_aim(
+    mut player_query: Query<&mut Pose, With<Player>>,
+    enemy_query: Query<&Enemy>,
+    levels: Res<Levels>,
+    movement_config: Res<MovementConfig>,
+    render_config: Res<RenderConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut motion: EventReader<MouseMotion>,
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+) {
+    let delta: f32 = motion.read().map(|event| event.delta.x).sum();
+    let mouse_moved = dead_zone(delta, movement_config.look_dead_zone) != 0.;
+    if movement_config.aim_assist == AimAssist::Off || movement_config.mode == MovementMode::GridLocked { return }
+    if mouse_moved || keyboard.pressed(key_bindings.turn_left) || keyboard.pressed(key_bindings.turn_right) { return }
+
+    let Ok(mut pose) = player_query.get_single_mut() else { return };
+    // signed angle from the current heading, taking the short way around
+    let turn_to = |target: f32| (target - pose.rotation + PI).rem_euclid(TAU) - PI;
+
+    let turn = match movement_config.aim_assist {
+        AimAssist::Off => return,
+        AimAssist::Cardinal => turn_to((pose.rotation / (PI / 2.)).round() * (PI / 2.)),
+        AimAssist::Enemy => {
+            let in_view = enemy_query.iter()
+                .filter(|enemy| has_line_of_sight(levels.active(), pose.position, enemy.position))
+                .map(|enemy| {
+                    let offset = enemy.position - pose.position;
+                    turn_to(offset.y.atan2(offset.x))
+                })
+                .filter(|turn| turn.abs() <= render_config.fov / 2.)
+                .min_by(|a, b| a.abs().total_cmp(&b.abs()));
+            let Some(turn) = in_view else { return };
+            turn
+        }
+    };
+
+    let amount = (movement_config.aim_assist_strength * time_scale.delta_seconds(&time)).min(1.);
+    pose.rotation = (pose.rotation + turn * amount) % TAU;
+}
+
+fn track_walked(player_query: Query<&Pose, With<Player>>, mut walked: ResMut<Walked>) {
+    walked.distance = 0.;
+    let Ok(player) = player_query.get_single() else { return };
+
+    let previous = walked.last_position.replace(player.position).unwrap_or(player.position);
+    let moved = player.position.distance(previous);
+
+    // a level switch teleports the player, which isn't walking
+    if moved <= 1. { walked.distance = moved }
+}
+
+fn update_head_bob(
+    render_config: Res<RenderConfig>,
+    movement_config: Res<MovementConfig>,
+    walked: Res<Walked>,
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+    mut head_bob: ResMut<HeadBob>,
+) {
+    let delta_seconds = time_scale.delta_seconds(&time);
+    let moved = walked.distance;
+
+    let target = if render_config.headbob && delta_seconds > 0. && movement_config.speed > 0. {
+        (moved / delta_seconds / movement_config.speed).min(1.)
+    } else {
+        0.
+    };
+    head_bob.intensity += (target - head_bob.intensity) * (10. * delta_seconds).min(1.);
+    head_bob.walked += moved;
+
+    let phase = head_bob.walked * render_config.headbob_frequency * TAU;
+    head_bob.offset = render_config.headbob_amplitude * head_bob.intensity * phase.sin();
+}
+
+// pushes the player out of any blocking entity they walked into, without pushing them into a wall
+fn collide_with_entities(
+    mut player_query: Query<&mut Pose, With<Player>>,
+    blocking_query: Query<(&Enemy, &Blocking)>,
+    levels: Res<Levels>,
+    movement_config: Res<MovementConfig>,
+) {
+    if !movement_config.entity_collision { return }
+
+    let Ok(mut pose) = player_query.get_single_mut() else { return };
+    let environment = levels.active();
+
+    for (enemy, blocking) in &blocking_query {
+        let separated = separate_circles(pose.position, PLAYER_RADIUS, enemy.position, blocking.radius);
+        if separated != pose.position {
+            pose.position = move_with_collision(environment, &movement_config, pose.position, separated - pose.position);
+        }
+    }
+}
+
+// where a circle at `position` has to move so it no longer overlaps the one at `other`, unchanged if they don't touch
+fn separate_circles(position: Vec2, radius: f32, other: Vec2, other_radius: f32) -> Vec2 {
+    let offset = position - other;
+    let distance = offset.length();
+    let min_distance = radius + other_radius;
+
+    if distance >= min_distance { return position }
+
+    // exactly on top of each other there's no direction to go, so pick one
+    let normal = if distance > 0. { offset / distance } else { Vec2::X };
+    other + normal * min_distance
+}
+
+// moves in sub-steps, pushing the player's circle back out of any wall it ends up overlapping after each,
+// so running into a wall at an angle slides along it instead of stopping dead
+fn move_with_collision(
+    environment: &Environment,
+    movement_config: &MovementConfig,
+    start_pos: Vec2,
+    delta: Vec2,
+) -> Vec2 {
+    let passable = |cell: (i32, i32), from: Vec2| {
+        !environment.blocks_movement_from(cell, from)
+            || (movement_config.step_climbing && environment.height(cell) <= movement_config.step_height)
+    };
+
+    // sub-steps no longer than half the radius so a fast move can't skip past a wall corner
+    let steps = (delta.length() / (PLAYER_RADIUS / 2.)).ceil().max(1.);
+    let step = delta / steps;
+
+    let mut position = start_pos;
+    for _ in 0..steps as u32 {
+        let from = position;
+        position = push_out_of_walls(from + step, PLAYER_RADIUS, movement_config.corner_radius, |cell| passable(cell, from));
+    }
+
+    position
+}
+
+// moves a circle out of every blocking cell it overlaps along the overlap normal, so only the
+// part of the motion going into a wall is removed and sliding along it keeps its full speed
+fn push_out_of_walls(position: Vec2, radius: f32, corner_radius: f32, passable: impl Fn((i32, i32)) -> bool) -> Vec2 {
+    let mut position = position;
+    let center = world_to_cell(position);
+
+    // straight neighbours first, once those are resolved flat walls can't snag on the diagonal cells' corners
+    for (dx, dy) in [(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)] {
+        let cell = (center.0 + dx, center.1 + dy);
+        if passable(cell) { continue }
+
+        let min = Vec2::new(cell.0 as f32, cell.1 as f32);
+        let max = min + Vec2::ONE;
+
+        // the corner nearest the circle is only rounded when both cells beside it are open, rounding the
+        // corners where two walls meet would leave dents in the middle of a flat wall
+        let side = (position - (min + Vec2::splat(0.5))).signum();
+        let rounded = corner_radius > 0.
+            && passable((cell.0 + side.x as i32, cell.1))
+            && passable((cell.0, cell.1 + side.y as i32));
+        let arc_center = min + Vec2::splat(0.5) + side * (0.5 - corner_radius);
+        let beyond = (position - arc_center) * side;
+
+        if rounded && beyond.x > 0. && beyond.y > 0. {
+            let offset = position - arc_center;
+            let reach = radius + corner_radius;
+            if offset.length() < reach {
+                position = arc_center + offset / offset.length() * reach;
+            }
+            continue;
+        }
+
+        let closest = position.clamp(min, max);
+        let offset = position - closest;
+        let distance = offset.length();
+
+        if distance >= radius { continue }
+
+        if distance > 0. {
+            position = closest + offset / distance * radius;
+        }
+        else {
+            // the center ended up inside the cell, leave through the nearest face
+            let exits = [
+                (position.x - min.x, Vec2::new(min.x - radius, position.y)),
+                (max.x - position.x, Vec2::new(max.x + radius, position.y)),
+                (position.y - min.y, Vec2::new(position.x, min.y - radius)),
+                (max.y - position.y, Vec2::new(position.x, max.y + radius)),
+            ];
+            position = exits.into_iter().min_by(|a, b| a.0.total_cmp(&b.0)).unwrap().1;
+        }
+    }
+
+    position
+}
+
+fn load_sounds(
+    asset_server: Res<AssetServer>,
+    audio_config: Res<AudioConfig>,
+    mut commands: Commands,
+) {
+    commands.insert_resource(SoundEffects {
+        fire: asset_server.load("sounds/fire.ogg"),
+        door_open: asset_server.load("sounds/door_open.ogg"),
+        door_close: asset_server.load("sounds/door_close.ogg"),
+        damage: asset_server.load("sounds/damage.ogg"),
+        pickup: asset_server.load("sounds/pickup.ogg"),
+        impact: asset_server.load("sounds/impact.ogg"),
+        footstep: asset_server.load("sounds/footstep.ogg"),
+        impacts: Material::ALL.into_iter().map(|material| (material, asset_server.load(format!("sounds/impact_{}.ogg", material.name())))).collect(),
+        footsteps: Material::ALL.into_iter().map(|material| (material, asset_server.load(format!("sounds/footstep_{}.ogg", material.name())))).collect(),
+    });
+
+    commands.spawn(AudioBundle {
+        source: asset_server.load("sounds/music.ogg"),
+        settings: PlaybackSettings::LOOP.with_volume(Volume::new(audio_config.music_volume * audio_config.master_volume)),
+    });
+}
+
+fn play_sound(commands: &mut Commands, source: &Handle<AudioSource>, volume: f32) {
+    commands.spawn(AudioBundle {
+        source: source.clone(),
+        settings: PlaybackSettings::DESPAWN.with_volume(Volume::new(volume)),
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn play_sound_effects(
+    player_query: Query<Entity, With<Player>>,
+    levels: Res<Levels>,
+    sounds: Res<SoundEffects>,
+    audio_config: Res<AudioConfig>,
+    mut shots: EventReader<ShotFired>,
+    mut impacts: EventReader<WallImpact>,
+    mut doors: EventReader<DoorMoved>,
+    mut damage: EventReader<DamageEvent>,
+    mut pickups: EventReader<ItemPickedUp>,
+    mut commands: Commands,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+    let master = audio_config.master_volume;
+
+    for _ in pickups.read() {
+        play_sound(&mut commands, &sounds.pickup, audio_config.pickup_volume * master);
+    }
+
+    for _ in shots.read() {
+        play_sound(&mut commands, &sounds.fire, audio_config.fire_volume * master);
+    }
+    for impact in impacts.read() {
+        let material = levels.active().kind(impact.cell).material();
+        let sound = material.and_then(|material| sounds.impacts.get(&material)).unwrap_or(&sounds.impact);
+        play_sound(&mut commands, sound, audio_config.impact_volume * master);
+    }
+    for door in doors.read() {
+        let sound = if door.opening { &sounds.door_open } else { &sounds.door_close };
+        play_sound(&mut commands, sound, audio_config.door_volume * master);
+    }
+    for event in damage.read() {
+        if event.target == player {
+            play_sound(&mut commands, &sounds.damage, audio_config.damage_volume * master);
+        }
+    }
+}
+
+// steps come from distance walked rather than time, so they speed up and slow down with the player
+fn play_footsteps(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    sounds: Res<SoundEffects>,
+    audio_config: Res<AudioConfig>,
+    walked: Res<Walked>,
+    mut since_step: Local<f32>,
+    mut commands: Commands,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+
+    *since_step += walked.distance;
+    if *since_step >= audio_config.footstep_stride {
+        *since_step -= audio_config.footstep_stride;
+        let material = levels.active().floor_material(world_to_cell(player.position));
+        let sound = material.and_then(|material| sounds.footsteps.get(&material)).unwrap_or(&sounds.footstep);
+        play_sound(&mut commands, sound, audio_config.footstep_volume * audio_config.master_volume);
+    }
+}
+
+fn apply_present_mode(
+    mut window_query: Query<&mut Window, With<PrimaryWindow>>,
+    render_config: Res<RenderConfig>,
+) {
+    if !render_config.is_changed() { return }
+    let Ok(mut window) = window_query.get_single_mut() else { return };
+
+    let present_mode = if render_config.vsync { PresentMode::AutoVsync } else { PresentMode::AutoNoVsync };
+    if window.present_mode != present_mode {
+        window.present_mode = present_mode;
+    }
+}
+
+// sleeps away whatever is left of the frame when it was faster than max_fps allows; sleeping overshoots
+// a little so the cap is rough, but enough to keep an empty scene from spinning at a thousand frames
+#[cfg(not(target_arch = "wasm32"))]
+fn limit_frame_rate(render_config: Res<RenderConfig>, mut frame_start: Local<Option<std::time::Instant>>) {
+    if let (Some(max_fps), Some(start)) = (render_config.max_fps, *frame_start) {
+        let frame_time = std::time::Duration::from_secs_f32(1. / max_fps);
+        if let Some(remaining) = frame_time.checked_sub(start.elapsed()) {
+            std::thread::sleep(remaining);
+        }
+    }
+
+    *frame_start = Some(std::time::Instant::now());
+}
+
+// the browser already paces frames to the display
+#[cfg(target_arch = "wasm32")]
+fn limit_frame_rate() {}
+
+// grabs the cursor when the window is clicked and lets it go on the release key or when focus is lost
+fn update_cursor_capture(
+    mut window_query: Query<&mut Window, With<PrimaryWindow>>,
+    mut capture: ResMut<CursorCapture>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut focus: EventReader<WindowFocused>,
+) {
+    let Ok(mut window) = window_query.get_single_mut() else { return };
+
+    let lost_focus = focus.read().any(|event| !event.focused);
+
+    let captured = if lost_focus || keyboard.just_pressed(key_bindings.release_cursor) {
+        false
+    } else if mouse.just_pressed(MouseButton::Left) && window.focused {
+        true
+    } else {
+        capture.captured
+    };
+
+    if captured == capture.captured { return }
+    capture.captured = captured;
+
+    window.cursor.grab_mode = if captured { CursorGrabMode::Locked } else { CursorGrabMode::None };
+    window.cursor.visible = !captured;
+}
+
+// runs before the capture is updated, so motion from the frame the cursor gets grabbed is thrown away
+fn mouse_look(
+    mut player_query: Query<&mut Pose, With<Player>>,
+    capture: Res<CursorCapture>,
+    movement_config: Res<MovementConfig>,
+    mut motion: EventReader<MouseMotion>,
+) {
+    // always drain the events, otherwise movement from while the cursor was free piles up and jumps the camera on capture
+    let delta: f32 = motion.read().map(|event| event.delta.x).sum();
+    if !capture.captured || movement_config.mode == MovementMode::GridLocked { return }
+
+    let Ok(mut pose) = player_query.get_single_mut() else { return };
+    let delta = dead_zone(delta, movement_config.look_dead_zone);
+    pose.rotation = (pose.rotation - delta * movement_config.mouse_sensitivity) % TAU;
+}
+
+// zero when the input is within the dead zone, otherwise passed through untouched so real input isn't damped
+fn dead_zone(value: f32, dead_zone: f32) -> f32 {
+    if value.abs() < dead_zone { 0. } else { value }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_scene(
+    window_query: Query<&Window>,
+    mut viewer_query: Query<(&Viewer, &Pose, &Elevation, &CameraRoll, &mut DepthBuffer, &mut WallKindBuffer)>,
+    levels: Res<Levels>,
+    debug_colors: Res<DebugColors>,
+    render_config: Res<RenderConfig>,
+    mut column_table: ResMut<ColumnTable>,
+    screen_shake: Res<ScreenShake>,
+    head_bob: Res<HeadBob>,
+    frozen_view: Res<FrozenView>,
+    mut gizmos: Gizmos,
+) {
+    if render_config.view_mode == ViewMode::Ascii { return }
+
+    let scale = 100.;
+
+    let Ok(window) = window_query.get_single() else { return };
+    let environment = levels.active();
+
+    for (&Viewer { slot }, player, elevation, roll, mut depth_buffer, mut kind_buffer) in &mut viewer_query {
+        // the top-down map is shared by every view, so it's only drawn once
+        if render_config.top_down() && slot > 0 { continue }
+
+        // only the first view can be frozen, the others always follow their player
+        let viewer = if slot == 0 { frozen_view.pose.as_ref().unwrap_or(player) } else { player };
+
+        let viewport = Viewport::new(window, &render_config, slot);
+        let view = ViewTransform::new(roll, &screen_shake, head_bob.offset_for(slot), viewport, &render_config);
+        // horizontal fov stays fixed on resize, the vertical view follows from the window's aspect ratio
+        let vertical_scale = render_config.camera_model.focal_length(viewport.width as u32, render_config.fov);
+        let eye_height = render_config.eye_height + elevation.height;
+
+        depth_buffer.distances.clear();
+        depth_buffer.distances.resize(viewport.width as usize, f32::INFINITY);
+        kind_buffer.kinds.clear();
+        kind_buffer.kinds.resize(viewport.width as usize, None);
+
+        if render_config.top_down() {
+            gizmos.arrow_2d(Vec2::ZERO, Vec2::X * scale, debug_colors.axes);
+            gizmos.arrow_2d(Vec2::ZERO, Vec2::Y * scale, debug_colors.axes);
+
+            gizmos.linestrip_gradient_2d([
+                (player.position * scale + Vec2::from_angle(player.rotation) * scale / 3., debug_colors.player_front),
+                (player.position * scale - Vec2::from_angle(player.rotation) * scale / 6. + Vec2::from_angle(player.rotation + PI / 2.) * scale / 6., debug_colors.player_left),
+                (player.position * scale - Vec2::from_angle(player.rotation) * scale / 6. - Vec2::from_angle(player.rotation + PI / 2.) * scale / 6., debug_colors.player_right),
+                (player.position * scale + Vec2::from_angle(player.rotation) * scale / 3., debug_colors.player_front),
+            ]);
+
+            // the cell world_to_cell puts the player in, to check the flooring on the negative side of the axes
+            let (cell_x, cell_y) = world_to_cell(player.position);
+            gizmos.rect_2d((Vec2::new(cell_x as f32, cell_y as f32) + Vec2::splat(0.5)) * scale, 0., Vec2::splat(scale * 0.9), debug_colors.player_cell);
+
+            for w in environment.iter_walls() {
+                let color = match environment.kind(w) {
+                    WallKind::Solid => debug_colors.walls,
+                    WallKind::AutoDoor => debug_colors.doors,
+                    WallKind::Glass => debug_colors.glass,
+                    WallKind::Window => debug_colors.windows,
+                    WallKind::FogWall => debug_colors.fog_walls,
+                    WallKind::Emissive => debug_colors.emissive_walls,
+                };
+
+                if DEBUG_MAP_FILLED_WALLS {
+                    // gizmos only draw outlines, so fill the cell with one line per pixel row
+                    let corner = Vec2::new(w.0 as f32, w.1 as f32) * scale;
+                    for row in 0..scale as i32 {
+                        let y = corner.y + row as f32 + 0.5;
+                        gizmos.line_2d(Vec2::new(corner.x, y), Vec2::new(corner.x + scale, y), color.with_a(0.4));
+                    }
+                }
+
+                gizmos.rect_2d(
+                    (Vec2::new(w.0 as f32, w.1 as f32) + Vec2::splat(0.5)) * scale,
+                    0.,
+                    Vec2::splat(scale),
+                    color,
+                );    
+            }
+
+            if DEBUG_VISIBLE_CELLS {
+                for cell in visible_cells(environment, player.position, player.rotation, render_config.fov, RAYCAST_DEPTH as f32, 64) {
+                    gizmos.rect_2d((Vec2::new(cell.0 as f32, cell.1 as f32) + Vec2::splat(0.5)) * scale, 0., Vec2::splat(scale * 0.8), debug_colors.visible_walls);
+                }
+            }
+
+            if DEBUG_VISIBILITY_POLYGON {
+                let polygon = visibility_polygon(environment, player.position);
+                let closed = polygon.iter().chain(polygon.first()).map(|&point| point * scale);
+                gizmos.linestrip_2d(closed, debug_colors.visibility_polygon);
+            }
+
+            // the edges of the frozen view, out to where they hit a wall
+            if let Some(frozen) = &frozen_view.pose {
+                for side in [-1., 1.] {
+                    let angle = frozen.rotation + side * render_config.fov / 2.;
+                    let length = cast_angle(environment, frozen.position, angle, RAYCAST_DEPTH as f32).map_or(RAYCAST_DEPTH as f32, |hit| hit.distance);
+                    gizmos.line_2d(frozen.position * scale, (frozen.position + Vec2::from_angle(angle) * length) * scale, debug_colors.frozen_view);
+                }
+                gizmos.circle_2d(frozen.position * scale, scale / 10., debug_colors.frozen_view);
+            }
+        }
+
+        let stride = if render_config.ray_count.is_some() { 1 } else { render_config.column_stride.max(1) as usize };
+        column_table.update(viewport.width as u32, &render_config);
+        let rays = column_table.rays(viewer, stride, &render_config);
+        let hits = column_hits(environment, &rays, &render_config);
+
+        let sky = levels.active_level().sky.as_deref().filter(|sky| !sky.is_empty());
+        let ambient = levels.active_level().ambient.unwrap_or(render_config.ambient);
+
+        for (&(column, ray_origin, ray_direction, angle), hit) in rays.iter().zip(hits) {
+            let column = column as i32;
+
+            // the column's world direction picks the sky color, so it stays put in the world while the view turns
+            let sky_color = sky.map(|sky| {
+                let u = (viewer.rotation + angle).rem_euclid(TAU) / TAU;
+                apply_gamma(sky[(u * sky.len() as f32) as usize % sky.len()], render_config.gamma)
+            });
+
+            let wall_distance = hit.map(|hit| hit.distance);
+            let strips = column..(column + stride as i32).min(viewport.width as i32);
+
+            if let Some(hit) = hit {
+                for strip in strips.clone() {
+                    depth_buffer.distances[strip as usize] = hit.distance;
+                    kind_buffer.kinds[strip as usize] = Some(environment.kind(hit.cell));
+                }
+            }
+
+            // floor and ceiling are drawn one segment per cell the ray crosses before it reaches the wall
+            if !render_config.top_down() && render_config.draw_floors {
+                let until = wall_distance.unwrap_or(RAYCAST_DEPTH as f32);
+                let cells = cells_along_ray(ray_origin, ray_direction, until);
+
+                // screen height of the point `distance` along the ray on a flat plane `height` above the floor
+                let plane_y = |distance: f32, height: f32| {
+                    let size = column_table.projected_height(column as usize, distance, vertical_scale);
+                    let y = match render_config.anchor {
+                        WallAnchor::Centered => size * (height - 0.5),
+                        WallAnchor::FloorAligned => size * (height - eye_height),
+                    };
+                    y.clamp(-viewport.height / 2., viewport.height / 2.)
+                };
+
+                // fog gathered on the way to the current segment, each segment is fogged as at its middle
+                let mut fog = 0.;
+
+                for (i, &(cell, near)) in cells.iter().enumerate() {
+                    let far = cells.get(i + 1).map_or(until, |next| next.1).min(until);
+                    if far <= near { continue }
+
+                    let density = environment.fog_density(cell);
+                    let segment_fog = fog + density * (far - near) / 2.;
+                    fog += density * (far - near);
+
+                    let (near_ceiling, far_ceiling) = (plane_y(near, 1.), plane_y(far, 1.));
+                    let (mut near_floor, mut far_floor) = (plane_y(near, 0.), plane_y(far, 0.));
+
+                    // a pit's floor is lowered, the edge nearest the camera hides the start of it
+                    // and its far side shows as a short wall going down from floor level
+                    let pit_depth = environment.floor_depth(cell);
+                    let mut pit_wall = None;
+                    if pit_depth > 0. {
+                        let lip = near_floor;
+                        near_floor = plane_y(near, -pit_depth).max(lip);
+                        far_floor = plane_y(far, -pit_depth).max(lip);
+
+                        let pit_wall_color = apply_gamma(Color::hsl(0., 0., (1.5 / far).max(ambient)), render_config.gamma);
+                        let pit_wall_color = apply_fog(pit_wall_color, fog, &render_config);
+                        pit_wall = Some((far_floor, plane_y(far, 0.).max(lip), pit_wall_color));
+                    }
+
+                    let shade = (1.5 / ((near + far) / 2.)).max(ambient).min(1.);
+                    let floor_color = apply_gamma(darken(environment.floor_color(cell).unwrap_or(render_config.floor_color), shade), render_config.gamma);
+                    let ceiling_color = apply_gamma(darken(environment.ceiling_color(cell).unwrap_or(render_config.ceiling_color), shade), render_config.gamma);
+                    let (floor_color, ceiling_color) = (apply_fog(floor_color, segment_fog, &render_config), apply_fog(ceiling_color, segment_fog, &render_config));
+
+                    for strip in strips.clone() {
+                        let x = viewport.width / 2. - strip as f32;
+                        if far_floor > near_floor {
+                            view.column_line(&mut gizmos, x, near_floor, far_floor, floor_color);
+                        }
+                        if let Some((bottom, top, color)) = pit_wall {
+                            if top > bottom {
+                                view.column_line(&mut gizmos, x, bottom, top, color);
+                            }
+                        }
+                        if sky_color.is_none() {
+                            view.column_line(&mut gizmos, x, far_ceiling, near_ceiling, ceiling_color);
+                        }
+                    }
+                }
+            }
+
+            if render_config.top_down() {
+                if let Some(wall_distance) = wall_distance {
+                    gizmos.line_2d(ray_origin * scale, (ray_origin + ray_direction * wall_distance) * scale, debug_colors.ray_hit);
+                }
+                else {
+                    gizmos.line_2d(ray_origin * scale, (ray_origin + ray_direction * 100.) * scale, debug_colors.ray_miss);
+                }
+
+                if DEBUG_XRAY_MODE {
+                    for hit in raycast_all(environment, ray_origin, ray_direction) {
+                        gizmos.circle_2d((ray_origin + ray_direction * hit.distance) * scale, scale / 20., debug_colors.xray_hit);
+                    }
+                }
+            }
+            else if let Some(hit) = hit {
+                let wall_distance = hit.distance;
+                let percieved_wall_size = column_table.projected_height(column as usize, wall_distance, vertical_scale);
+
+                let wall_color = hit_color(environment, &hit, angle, ambient, &render_config);
+                let wall_color = apply_fog(wall_color, wall_fog(environment, &hit, ray_origin, ray_direction), &render_config);
+
+                let (wall_bottom, wall_top) = wall_span(percieved_wall_size, environment.height(hit.cell), eye_height, render_config.anchor);
+
+                // a door slides up as it opens, the wall behind it shows through the gap underneath
+                let wall_bottom = wall_bottom + environment.openness(hit.cell) * (wall_top - wall_bottom);
+
+                // walls right in front of the camera would otherwise reach far past the screen edges
+                let half_height = viewport.height / 2.;
+                let (wall_bottom, wall_top) = (wall_bottom.max(-half_height), wall_top.min(half_height));
+
+                let mode = render_mode(environment.kind(hit.cell), &render_config);
+
+                let behind = if environment.openness(hit.cell) > 0. || mode != RenderMode::Opaque {
+                    raycast_all(environment, ray_origin, ray_direction).get(1).map(|behind| {
+                        let behind_size = column_table.projected_height(column as usize, behind.distance, vertical_scale);
+                        let behind_color = hit_color(environment, behind, angle, ambient, &render_config);
+                        let behind_color = apply_fog(behind_color, wall_fog(environment, behind, ray_origin, ray_direction), &render_config);
+                        let (behind_bottom, behind_top) = wall_span(behind_size, environment.height(behind.cell), eye_height, render_config.anchor);
+                        // an opaque door only shows what's behind it through the gap underneath
+                        let top = if mode == RenderMode::Opaque { wall_bottom } else { half_height };
+                        (behind_bottom.max(-half_height), behind_top.min(top), behind_color)
+                    })
+                } else {
+                    None
+                };
+
+                // every strip of the stride gets the same cast so no screen columns are left empty
+                for strip in strips {
+                    let x = viewport.width / 2. - strip as f32;
+
+                    if let Some(sky_color) = sky_color {
+                        view.column_line(&mut gizmos, x, wall_top, half_height, sky_color);
+                    }
+
+                    if let Some((behind_bottom, behind_top, behind_color)) = behind {
+                        if behind_top - behind_bottom >= MIN_STRIP_HEIGHT {
+                            view.column_line(&mut gizmos, x, behind_bottom, behind_top, behind_color);
+                        }
+                    }
+
+                    // far away walls and almost open doors can end up thinner than a pixel, or even inverted
+                    if wall_top - wall_bottom >= MIN_STRIP_HEIGHT {
+                        match mode {
+                            RenderMode::Opaque => {
+                                view.column_line(&mut gizmos, x, wall_bottom, wall_top, wall_color);
+                            }
+                            RenderMode::ScreenDoor { alpha } => {
+                                for (bottom, top) in screen_door_runs(strip, wall_bottom, wall_top, alpha) {
+                                    view.column_line(&mut gizmos, x, bottom, top, wall_color);
+                                }
+                            }
+                        }
+                    }
+                }
+        
+            }
+            else if let Some(sky_color) = sky_color {
+                // nothing in the way, the sky comes down to the horizon
+                for strip in strips {
+                    view.column_line(&mut gizmos, viewport.width / 2. - strip as f32, 0., viewport.height / 2., sky_color);
+                }
+            }
+        }
+    }
+}
+fn update_wall_kind_label(
+    kind_query: Query<&WallKindBuffer, With<Player>>,
+    mut label_query: Query<&mut Text, With<WallKindLabel>>,
+) {
+    let Ok(mut label) = label_query.get_single_mut() else { return };
+    let Ok(kind_buffer) = kind_query.get_single() else { return };
+
+    label.sections[0].value = if DEBUG_WALL_KIND_LABEL {
+        match kind_buffer.kinds.get(kind_buffer.kinds.len() / 2) {
+            Some(Some(kind)) => format!("{:?}", kind),
+            _ => "-".to_string(),
+        }
+    } else {
+        String::new()
+    };
+}
+
+// the player's heading as a compass bearing, +y being north and degrees going clockwise like on a real compass
+fn update_compass(
+    player_query: Query<&Pose, With<Player>>,
+    compass_config: Res<CompassConfig>,
+    mut compass_query: Query<(&mut Text, &mut Style), With<Compass>>,
+) {
+    let Ok((mut text, mut style)) = compass_query.get_single_mut() else { return };
+
+    if !compass_config.enabled {
+        text.sections[0].value = String::new();
+        return;
+    }
+
+    let Ok(player) = player_query.get_single() else { return };
+    // rem_euclid keeps rotations of any sign or number of turns in 0..360
+    let bearing = (90. - player.rotation.to_degrees()).rem_euclid(360.);
+    let points = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+    let point = points[(bearing / 45.).round() as usize % points.len()];
+
+    text.sections[0].value = format!("{} {:03.0}\u{b0}", point, bearing.round() % 360.);
+    text.sections[0].style.font_size = compass_config.font_size;
+    text.sections[0].style.color = compass_config.color;
+
+    let margin = Val::Px(compass_config.margin);
+    let (top, bottom) = match compass_config.corner {
+        ScreenCorner::TopLeft | ScreenCorner::TopRight => (margin, Val::Auto),
+        ScreenCorner::BottomLeft | ScreenCorner::BottomRight => (Val::Auto, margin),
+    };
+    let (left, right) = match compass_config.corner {
+        ScreenCorner::TopLeft | ScreenCorner::BottomLeft => (margin, Val::Auto),
+        ScreenCorner::TopRight | ScreenCorner::BottomRight => (Val::Auto, margin),
+    };
+    (style.top, style.bottom, style.left, style.right) = (top, bottom, left, right);
+}
+
+// origin, direction and angle off center of the ray for one screen column
+fn column_ray(viewer: &Pose, column: u32, render_width: u32, render_config: &RenderConfig) -> (Vec2, Vec2, f32) {
+    match render_config.projection {
+        ProjectionMode::Perspective => (
+            viewer.position,
+            render_config.camera_model.column_direction(column, render_width, render_config.fov, viewer.rotation),
+            render_config.camera_model.column_angle(column, render_width, render_config.fov),
+        ),
+        ProjectionMode::Orthographic => {
+            let forward = Vec2::from_angle(viewer.rotation);
+            let offset = (column as f32 - render_width as f32 / 2.) / render_width as f32 * render_config.orthographic_width;
+            (viewer.position + forward.perp() * offset, forward, 0.)
+        }
+    }
+}
+
+// column_ray for every `stride`th screen column, left edge first, with the column in front
+fn column_rays<'a>(viewer: &'a Pose, render_width: u32, stride: usize, render_config: &'a RenderConfig) -> impl DoubleEndedIterator<Item = (u32, Vec2, Vec2, f32)> + 'a {
+    (0..render_width).step_by(stride).map(move |column| {
+        let (origin, direction, angle) = column_ray(viewer, column, render_width, render_config);
+        (column, origin, direction, angle)
+    })
+}
+
+// the wall hit by each of `rays`; with ray_count set only that many of them are cast, a ray between two casts that
+// hit the same face of the same cell meets that face too, anywhere else it takes the nearer cast's hit
+fn column_hits(environment: &Environment, rays: &[(u32, Vec2, Vec2, f32)], render_config: &RenderConfig) -> Vec<Option<RaycastHit>> {
+    let cast = |&(_, origin, direction, _): &(u32, Vec2, Vec2, f32)| raycast(environment, origin, direction);
+
+    let Some(ray_count) = render_config.ray_count else { return rays.iter().map(cast).collect() };
+    if rays.len() <= 2 { return rays.iter().map(cast).collect() }
+
+    let last = rays.len() - 1;
+    let samples = (ray_count as usize).clamp(2, rays.len());
+    let mut sampled: Vec<usize> = (0..samples).map(|i| i * last / (samples - 1)).collect();
+    sampled.dedup();
+
+    let sampled_hits: Vec<Option<RaycastHit>> = sampled.iter().map(|&i| cast(&rays[i])).collect();
+    let mut hits = vec![None; rays.len()];
+
+    for (pair, pair_hits) in sampled.windows(2).zip(sampled_hits.windows(2)) {
+        let (low, high) = (pair[0], pair[1]);
+        let same_face = match (pair_hits[0], pair_hits[1]) {
+            (Some(a), Some(b)) => a.cell == b.cell && a.normal == b.normal,
+            _ => false,
+        };
+
+        hits[low] = pair_hits[0];
+        hits[high] = pair_hits[1];
+
+        for (i, hit) in hits.iter_mut().enumerate().take(high).skip(low + 1) {
+            let nearer = if i - low <= high - i { pair_hits[0] } else { pair_hits[1] };
+            let (_, origin, direction, _) = rays[i];
+            *hit = if same_face { nearer.and_then(|nearer| nearer.on_face(origin, direction)).or(nearer) } else { nearer };
+        }
+    }
+
+    hits
+}
+
+fn render_mode(kind: WallKind, render_config: &RenderConfig) -> RenderMode {
+    match kind {
+        WallKind::Glass => render_config.glass,
+        WallKind::Solid | WallKind::AutoDoor | WallKind::Window | WallKind::FogWall | WallKind::Emissive => RenderMode::Opaque,
+    }
+}
+
+// the closest and second closest colors of `palette` to `color` in rgb, and how far `color` lies from the
+// first toward the second as a 0 to 1 fraction; None for an empty palette
+fn nearest_palette_colors(palette: &[Color], color: Color) -> Option<(Color, Color, f32)> {
+    let rgb = |color: Color| {
+        let [r, g, b, _] = color.as_rgba_f32();
+        Vec3::new(r, g, b)
+    };
+    let target = rgb(color);
+
+    let mut by_distance: Vec<Color> = palette.to_vec();
+    by_distance.sort_by(|a, b| rgb(*a).distance_squared(target).total_cmp(&rgb(*b).distance_squared(target)));
+
+    let nearest = *by_distance.first()?;
+    let second = by_distance.get(1).copied().unwrap_or(nearest);
+
+    let span = rgb(second) - rgb(nearest);
+    let blend = if span.length_squared() > 0. { ((target - rgb(nearest)).dot(span) / span.length_squared()).clamp(0., 1.) } else { 0. };
+
+    // the palette colors keep their own alpha, the drawn color's alpha still decides how see-through it is
+    Some((nearest.with_a(color.a()), second.with_a(color.a()), blend))
+}
+
+// ordered dither thresholds, looked up by screen position so the pattern holds still while the view does
+const SCREEN_DOOR_PATTERN: [[f32; 4]; 4] = [
+    [0., 8., 2., 10.],
+    [12., 4., 14., 6.],
+    [3., 11., 1., 9.],
+    [15., 7., 13., 5.],
+];
+
+// the pieces of the strip from `bottom` to `top` in screen column `column` that a screen door with
+// `alpha` coverage keeps, neighbouring kept pixels are merged into one piece
+fn screen_door_runs(column: i32, bottom: f32, top: f32, alpha: f32) -> Vec<(f32, f32)> {
+    let mut runs: Vec<(f32, f32)> = Vec::new();
+
+    for row in bottom.floor() as i32..top.ceil() as i32 {
+        let threshold = (SCREEN_DOOR_PATTERN[column.rem_euclid(4) as usize][row.rem_euclid(4) as usize] + 0.5) / 16.;
+        if threshold > alpha { continue }
+
+        let (from, to) = ((row as f32).max(bottom), (row as f32 + 1.).min(top));
+        match runs.last_mut() {
+            Some(run) if run.1 == from => run.1 = to,
+            _ => runs.push((from, to)),
+        }
+    }
+
+    runs
+}
+
+// bottom and top screen y of a wall strip, `size` being the projected height of a 1-tall wall
+fn wall_span(size: f32, height: f32, eye_height: f32, anchor: WallAnchor) -> (f32, f32) {
+    match anchor {
+        WallAnchor::Centered => (-size * height / 2., size * height / 2.),
+        WallAnchor::FloorAligned => (-size * eye_height, size * (height - eye_height)),
+    }
+}
+
+fn toggle_render_options(
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut render_config: ResMut<RenderConfig>,
+    mut roll_config: ResMut<CameraRollConfig>,
+    mut screen_shake: ResMut<ScreenShake>,
+    mut minimap_config: ResMut<MinimapConfig>,
+    mut compass_config: ResMut<CompassConfig>,
+) {
+    if keyboard.just_pressed(key_bindings.toggle_projection) {
+        render_config.projection = match render_config.projection {
+            ProjectionMode::Perspective => ProjectionMode::Orthographic,
+            ProjectionMode::Orthographic => ProjectionMode::Perspective,
+        };
+    }
+    if keyboard.just_pressed(key_bindings.toggle_anchor) {
+        render_config.anchor = match render_config.anchor {
+            WallAnchor::Centered => WallAnchor::FloorAligned,
+            WallAnchor::FloorAligned => WallAnchor::Centered,
+        };
+    }
+    if keyboard.just_pressed(key_bindings.toggle_camera_model) {
+        render_config.camera_model = match render_config.camera_model {
+            CameraModel::PlanarColumns => CameraModel::AngularColumns,
+            CameraModel::AngularColumns => CameraModel::PlanarColumns,
+        };
+    }
+    if keyboard.just_pressed(key_bindings.toggle_camera_roll) {
+        roll_config.enabled = !roll_config.enabled;
+    }
+    if keyboard.just_pressed(key_bindings.toggle_screen_shake) {
+        screen_shake.enabled = !screen_shake.enabled;
+    }
+    if keyboard.just_pressed(key_bindings.toggle_minimap) {
+        minimap_config.enabled = !minimap_config.enabled;
+    }
+    if keyboard.just_pressed(key_bindings.toggle_headbob) {
+        render_config.headbob = !render_config.headbob;
+    }
+    if keyboard.just_pressed(key_bindings.toggle_compass) {
+        compass_config.enabled = !compass_config.enabled;
+    }
+    if keyboard.just_pressed(key_bindings.toggle_flashlight) {
+        render_config.flashlight = !render_config.flashlight;
+    }
+    if keyboard.just_pressed(key_bindings.cycle_view_mode) {
+        render_config.view_mode = render_config.view_mode.next();
+        info!("view mode {:?}", render_config.view_mode);
+    }
+}
+
+fn adjust_time_scale(
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut time_scale: ResMut<TimeScale>,
+) {
+    let scale = if keyboard.just_pressed(key_bindings.reset_time_scale) {
+        1.
+    } else if keyboard.just_pressed(key_bindings.slow_down) {
+        time_scale.scale / time_scale.step
+    } else if keyboard.just_pressed(key_bindings.speed_up) {
+        time_scale.scale * time_scale.step
+    } else {
+        return;
+    };
+
+    time_scale.scale = scale.clamp(time_scale.min, time_scale.max);
+    info!("time scale {}", time_scale.scale);
+}
+
+// logs the walls of the current level that can't be seen from anywhere the player can walk to, so map
+// authors can trim them or find areas that were sealed off by mistake
+fn report_coverage(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if !keyboard.just_pressed(key_bindings.coverage_report) { return }
+
+    let Ok(player) = player_query.get_single() else { return };
+    let environment = levels.active();
+    let unseen = unseen_walls(environment, world_to_cell(player.position), 360);
+
+    if unseen.is_empty() {
+        info!("all {} walls of level {} can be seen", environment.wall_count(), levels.active);
+    } else {
+        info!("{} of {} walls of level {} are never visible: {:?}", unseen.len(), environment.wall_count(), levels.active, unseen);
+    }
+}
+
+fn follow_camera_path(
+    mut player_query: Query<&mut Pose, With<Player>>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    mut path: ResMut<CameraPath>,
+) {
+    if keyboard.just_pressed(key_bindings.play_camera_path) {
+        path.elapsed = match path.elapsed {
+            Some(_) => None,
+            None => load_camera_path(Path::new(CAMERA_PATH_PATH)).map(|keyframes| {
+                path.keyframes = keyframes;
+                0.
+            }),
+        };
+    }
+
+    let Some(elapsed) = path.elapsed else { return };
+    let Ok(mut pose) = player_query.get_single_mut() else { return };
+
+    let elapsed = elapsed + time.delta_seconds();
+    let Some(last) = path.keyframes.last().copied() else { return };
+    if elapsed >= last.time {
+        (pose.position, pose.rotation) = (last.position, last.rotation);
+        path.elapsed = None;
+        return;
+    }
+
+    // the keyframes either side of now, before the first one the path holds at its start
+    let next = path.keyframes.iter().position(|keyframe| keyframe.time > elapsed).unwrap_or(0);
+    let from = path.keyframes[next.saturating_sub(1)];
+    let to = path.keyframes[next];
+    let t = if to.time > from.time { ((elapsed - from.time) / (to.time - from.time)).clamp(0., 1.) } else { 1. };
+
+    // the short way around, so a path from just below TAU to just above 0 doesn't spin all the way back
+    let turn = (to.rotation - from.rotation + PI).rem_euclid(TAU) - PI;
+    pose.position = from.position.lerp(to.position, t);
+    pose.rotation = (from.rotation + turn * t) % TAU;
+    path.elapsed = Some(elapsed);
+}
+
+fn load_camera_path(path: &Path) -> Option<Vec<Keyframe>> {
+    let Some(contents) = read_config_file(path) else {
+        warn!("no camera path at {}", path.display());
+        return None;
+    };
+
+    match ron::from_str::<Vec<Keyframe>>(&contents) {
+        Ok(keyframes) if !keyframes.is_empty() => Some(keyframes),
+        Ok(_) => {
+            warn!("{} has no keyframes", path.display());
+            None
+        }
+        Err(error) => {
+            warn!("couldn't parse {}: {error}", path.display());
+            None
+        }
+    }
+}
+
+fn toggle_frozen_view(
+    player_query: Query<&Pose, With<Player>>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut frozen_view: ResMut<FrozenView>,
+) {
+    if !keyboard.just_pressed(key_bindings.freeze_view) { return }
+
+    frozen_view.pose = match frozen_view.pose {
+        Some(_) => None,
+        None => player_query.get_single().ok().copied(),
+    };
+    match frozen_view.pose {
+        Some(pose) => info!("rays frozen at {:?} facing {:.3}", pose.position, pose.rotation),
+        None => info!("rays follow the player again"),
+    }
+}
+
+// only counts anything when built with the raycast_stats feature
+fn log_raycast_stats(
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    mut log: ResMut<RaycastStatsLog>,
+) {
+    if keyboard.just_pressed(key_bindings.raycast_stats) {
+        log.enabled = !log.enabled;
+        if log.enabled && !cfg!(feature = "raycast_stats") {
+            warn!("built without the raycast_stats feature, all raycast stats will be zero");
+        }
+    }
+
+    // taken every frame so nothing piles up while the log is off
+    let frame = take_raycast_stats();
+    if !log.enabled { return }
+
+    log.totals.rays += frame.rays;
+    log.totals.steps += frame.steps;
+    log.totals.max_steps = log.totals.max_steps.max(frame.max_steps);
+    log.totals.misses += frame.misses;
+    log.totals.capped += frame.capped;
+    log.frames += 1;
+    log.elapsed += time.delta_seconds();
+
+    if log.elapsed < 1. { return }
+
+    let frames = log.frames as f32;
+    info!(
+        "{:.0} rays per frame, {:.1} steps per ray (at most {}), {:.0} misses and {:.0} capped at RAYCAST_DEPTH per frame",
+        log.totals.rays as f32 / frames, log.totals.average_steps(), log.totals.max_steps,
+        log.totals.misses as f32 / frames, log.totals.capped as f32 / frames,
+    );
+    *log = RaycastStatsLog { enabled: true, ..default() };
+}
+
+// right click a column or step with the select keys to print how its ray walks the grid
+#[allow(clippy::too_many_arguments)]
+fn select_debug_ray(
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    render_config: Res<RenderConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    mut selected: ResMut<SelectedRay>,
+) {
+    if !DEBUG_SELECTED_RAY { return }
+
+    let Ok(window) = window_query.get_single() else { return };
+    let viewport = Viewport::new(window, &render_config, 0);
+    let width = viewport.width as u32;
+    if width == 0 { return }
+    let current = selected.column.unwrap_or(width / 2).min(width - 1);
+
+    let column = if mouse.just_pressed(MouseButton::Right) {
+        let Some(cursor) = window.cursor_position() else { return };
+        (viewport.column_at(cursor.x, window.resolution.width()).max(0.) as u32).min(width - 1)
+    } else if keyboard.just_pressed(key_bindings.select_ray_left) {
+        (current + 1).min(width - 1)
+    } else if keyboard.just_pressed(key_bindings.select_ray_right) {
+        current.saturating_sub(1)
+    } else {
+        return;
+    };
+    selected.column = Some(column);
+
+    let Ok(player) = player_query.get_single() else { return };
+    let environment = levels.active();
+    let (origin, direction, angle) = column_ray(player, column, width, &render_config);
+    let hit = raycast(environment, origin, direction);
+
+    info!("ray {column}: from {origin:?} towards {direction:?}, {angle:.3} rad off center");
+    for (cell, distance) in cells_along_ray(origin, direction, hit.map_or(RAYCAST_DEPTH as f32, |hit| hit.distance)) {
+        info!("  enters {cell:?} at {distance:.3}");
+    }
+    match hit {
+        Some(hit) => info!(
+            "  hits {:?} ({:?}) at {:.3} on its {:?} face, {:.3} along it",
+            hit.cell, environment.kind(hit.cell), hit.distance, hit.face, hit.face_offset(),
+        ),
+        None => info!("  hits nothing"),
+    }
+}
+
+// world position under a window pixel in ViewMode::TopDown, where the map is drawn around the window center
+fn debug_map_position(window: &Window, cursor: Vec2) -> Vec2 {
+    let scale = 100.;
+    Vec2::new(cursor.x - window.resolution.width() / 2., window.resolution.height() / 2. - cursor.y) / scale
+}
+
+// puts the player in the middle of the cell under the cursor, or the nearest open one if that's a wall,
+// keeping the way they face
+#[allow(clippy::too_many_arguments)]
+fn teleport_to_cursor(
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    mut player_query: Query<&mut Pose, With<Player>>,
+    levels: Res<Levels>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    render_config: Res<RenderConfig>,
+    mut grid_step: ResMut<GridStep>,
+) {
+    if !render_config.top_down() { return }
+    if !mouse.just_pressed(MouseButton::Middle) && !keyboard.just_pressed(key_bindings.teleport) { return }
+
+    let Ok(window) = window_query.get_single() else { return };
+    let Some(cursor) = window.cursor_position() else { return };
+    let Ok(mut pose) = player_query.get_single_mut() else { return };
+
+    let target = debug_map_position(window, cursor);
+    let Some(cell) = nearest_open_cell(levels.active(), target) else { return };
+
+    pose.position = Vec2::new(cell.0 as f32, cell.1 as f32) + Vec2::splat(0.5);
+    // a grid step in progress would carry on from where the player was
+    grid_step.tween = None;
+    info!("teleported to {cell:?}");
+}
+
+fn draw_selected_ray(
+    window_query: Query<&Window>,
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    render_config: Res<RenderConfig>,
+    debug_colors: Res<DebugColors>,
+    selected: Res<SelectedRay>,
+    mut gizmos: Gizmos,
+) {
+    if !DEBUG_SELECTED_RAY { return }
+    let Some(column) = selected.column else { return };
+
+    let Ok(window) = window_query.get_single() else { return };
+    let Ok(player) = player_query.get_single() else { return };
+    let viewport = Viewport::new(window, &render_config, 0);
+
+    if !render_config.top_down() {
+        let x = viewport.width / 2. - column as f32;
+        let half_height = viewport.height / 2.;
+        let color = debug_colors.selected_ray.with_a(0.5);
+        gizmos.line_2d(viewport.window_point(Vec2::new(x, -half_height)), viewport.window_point(Vec2::new(x, half_height)), color);
+        return;
+    }
+
+    let scale = 100.;
+    let environment = levels.active();
+    let (origin, direction, _) = column_ray(player, column, viewport.width as u32, &render_config);
+    let length = raycast(environment, origin, direction).map_or(RAYCAST_DEPTH as f32, |hit| hit.distance);
+
+    gizmos.line_2d(origin * scale, (origin + direction * length) * scale, debug_colors.selected_ray);
+    for (cell, distance) in cells_along_ray(origin, direction, length) {
+        gizmos.rect_2d((Vec2::new(cell.0 as f32, cell.1 as f32) + Vec2::splat(0.5)) * scale, 0., Vec2::splat(scale * 0.9), debug_colors.selected_ray);
+        gizmos.circle_2d((origin + direction * distance) * scale, scale / 20., debug_colors.selected_ray);
+    }
+}
+
+// prints the current view to stdout with one character per column, denser characters for closer walls
+fn print_ascii_view(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    render_config: Res<RenderConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if !keyboard.just_pressed(key_bindings.print_view) { return }
+
+    let Ok(player) = player_query.get_single() else { return };
+    println!("{}", ascii_view(player, levels.active(), &render_config));
+}
+
+// shows the ASCII view on screen while ViewMode::Ascii is on
+fn update_ascii_view(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    render_config: Res<RenderConfig>,
+    mut text_query: Query<&mut Text, With<AsciiView>>,
+) {
+    let Ok(mut text) = text_query.get_single_mut() else { return };
+    let Ok(player) = player_query.get_single() else { return };
+
+    text.sections[0].value = if render_config.view_mode == ViewMode::Ascii {
+        ascii_view(player, levels.active(), &render_config)
+    } else {
+        String::new()
+    };
+}
+
+// the first-person view as an 80 by 24 grid of characters, walls drawn darker the closer they are
+fn ascii_view(player: &Pose, environment: &Environment, render_config: &RenderConfig) -> String {
+    let (columns, rows) = (80, 24);
+    let shades: Vec<char> = "@%#*+=-:.".chars().collect();
+
+    let walls: Vec<Option<(f32, f32)>> = column_rays(player, columns, 1, render_config).rev().map(|(_, origin, direction, angle)| {
+        raycast(environment, origin, direction).map(|hit| {
+            // same scaling as draw_scene, with the terminal height standing in for the window height
+            let height = render_config.camera_model.projected_height(hit.distance, angle, rows as f32);
+            (height, hit.distance)
+        })
+    }).collect();
+
+    let mut lines = Vec::new();
+    for row in 0..rows {
+        let y = rows as f32 / 2. - row as f32 - 0.5;
+
+        let line: String = walls.iter().map(|wall| match wall {
+            Some((height, distance)) if y.abs() < height / 2. => shades[(*distance as usize).min(shades.len() - 1)],
+            _ => ' ',
+        }).collect();
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+// everything needed to reproduce what the player is looking at
+#[derive(Serialize)]
+struct StateDump<'a> {
+    level: usize,
+    environment: &'a Environment,
+    player: &'a Pose,
+    render: &'a RenderConfig,
+    movement: &'a MovementConfig,
+}
+
+fn dump_state(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    render_config: Res<RenderConfig>,
+    movement_config: Res<MovementConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    if !keyboard.just_pressed(key_bindings.dump_state) { return }
+
+    let Ok(player) = player_query.get_single() else { return };
+    let dump = StateDump {
+        level: levels.active,
+        environment: levels.active(),
+        player,
+        render: &render_config,
+        movement: &movement_config,
+    };
+
+    match ron::ser::to_string_pretty(&dump, ron::ser::PrettyConfig::default()) {
+        Ok(contents) => write_dump(&contents),
+        Err(error) => warn!("couldn't serialize the current state: {error}"),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn write_dump(contents: &str) {
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let path = format!("dump-{timestamp}.ron");
+
+    match std::fs::write(&path, contents) {
+        Ok(()) => info!("dumped the current state to {path}"),
+        Err(error) => warn!("couldn't dump the current state to {path}: {error}"),
+    }
+}
+
+// no files to write to in the browser, and SystemTime isn't available there either, so it goes to the console
+#[cfg(target_arch = "wasm32")]
+fn write_dump(contents: &str) {
+    info!("current state:\n{contents}");
+}
+
+fn shake_on_damage(
+    player_query: Query<Entity, With<Player>>,
+    mut damage: EventReader<DamageEvent>,
+    mut screen_shake: ResMut<ScreenShake>,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+
+    for event in damage.read() {
+        if event.target == player {
+            let kick = screen_shake.damage_kick;
+            screen_shake.kick(kick);
+        }
+    }
+}
+
+fn update_screen_shake(
+    mut screen_shake: ResMut<ScreenShake>,
+    mut rng: ResMut<GameRng>,
+    time: Res<Time>,
+) {
+    screen_shake.magnitude *= (-screen_shake.decay * time.delta_seconds()).exp();
+
+    if !screen_shake.enabled || screen_shake.magnitude < 0.1 {
+        screen_shake.magnitude = 0.;
+        screen_shake.offset = Vec2::ZERO;
+        return;
+    }
+
+    let jitter = Vec2::new(rng.0.gen_range(-1.0..1.0), rng.0.gen_range(-1.0..1.0));
+    screen_shake.offset = jitter * screen_shake.magnitude;
+}
+
+fn animate_auto_doors(
+    player_query: Query<&Pose, With<Player>>,
+    mut levels: ResMut<Levels>,
+    door_config: Res<DoorConfig>,
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+    mut moved: EventWriter<DoorMoved>,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+    let environment = levels.active_mut();
+
+    let doors: Vec<(i32, i32)> = environment.kinds.iter()
+        .filter(|(_, kind)| **kind == WallKind::AutoDoor)
+        .map(|(cell, _)| *cell)
+        .collect();
+
+    for door in doors {
+        // only the player's distance counts, so nothing else can hold a door or make it flicker
+        let center = Vec2::new(door.0 as f32, door.1 as f32) + Vec2::splat(0.5);
+        let target = if player.position.distance(center) < door_config.open_distance { 1. } else { 0. };
+
+        let openness = environment.openness(door);
+        if (openness == 0. && target == 1.) || (openness == 1. && target == 0.) {
+            moved.send(DoorMoved { opening: target == 1. });
+        }
+
+        let step = door_config.speed * time_scale.delta_seconds(&time);
+        let openness = if target > openness { (openness + step).min(target) } else { (openness - step).max(target) };
+
+        environment.door_openness.insert(door, openness);
+    }
+}
+
+fn update_triggers(
+    player_query: Query<&Pose, With<Player>>,
+    trigger_query: Query<(Entity, &TriggerRegion)>,
+    levels: Res<Levels>,
+    mut inside: Local<HashSet<Entity>>,
+    mut entered: EventWriter<TriggerEntered>,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+    let cell = IVec2::from(world_to_cell(player.position));
+
+    // each region keeps its own inside/outside state, so overlapping regions fire independently
+    for (entity, region) in &trigger_query {
+        let contains = region.level == levels.active && cell.cmpge(region.min).all() && cell.cmple(region.max).all();
+
+        if contains && inside.insert(entity) {
+            entered.send(TriggerEntered(entity));
+        }
+        else if !contains {
+            inside.remove(&entity);
+        }
+    }
+
+    inside.retain(|entity| trigger_query.contains(*entity));
+}
+
+fn log_triggers(mut entered: EventReader<TriggerEntered>) {
+    for TriggerEntered(entity) in entered.read() {
+        info!("player entered trigger region {:?}", entity);
+    }
+}
+
+fn hit_color(environment: &Environment, hit: &RaycastHit, angle: f32, ambient: f32, render_config: &RenderConfig) -> Color {
+    if render_config.view_mode == ViewMode::Depth {
+        depth_color(hit.distance, render_config.depth_view_range)
+    } else if render_config.view_mode == ViewMode::Flat {
+        flat_wall(hit.normal, environment.brightness(hit.cell, hit.normal), render_config)
+    } else if environment.kind(hit.cell).shaded() {
+        shade_wall(hit.distance, angle, environment.brightness(hit.cell, hit.normal), ambient, render_config)
+    } else {
+        apply_gamma(environment.wall_color(hit.cell), render_config.gamma)
+    }
+}
+
+// grey that fades with distance and is scaled by the wall's baked `brightness`, kept from going below `ambient`,
+// plus the flashlight for walls `angle` off the view center; depth desaturation and gamma come last
+fn shade_wall(distance: f32, angle: f32, brightness: f32, ambient: f32, render_config: &RenderConfig) -> Color {
+    let lightness = (3. / distance * brightness).max(ambient) + flashlight_boost(distance, angle, render_config);
+    apply_gamma(apply_depth_desaturation(Color::hsl(0., 0., lightness), distance, render_config), render_config.gamma)
+}
+
+// the same grey at any distance, a shade darker on east and west faces so corners still read
+fn flat_wall(normal: Normal, brightness: f32, render_config: &RenderConfig) -> Color {
+    let side = match normal {
+        Normal::North | Normal::South => 1.,
+        Normal::East | Normal::West => 0.7,
+    };
+    apply_gamma(Color::hsl(0., 0., 0.6 * side * brightness), render_config.gamma)
+}
+
+// false color for a hit `distance` away, through the hues from blue to red as it goes from 0 to `range`
+fn depth_color(distance: f32, range: f32) -> Color {
+    let far = (distance / range.max(f32::EPSILON)).clamp(0., 1.);
+    Color::hsl(240. * (1. - far), 1., 0.5)
+}
+
+fn flashlight_boost(distance: f32, angle: f32, render_config: &RenderConfig) -> f32 {
+    if !render_config.flashlight { return 0. }
+
+    let off_center = angle.abs() / (render_config.flashlight_angle / 2.);
+    let far = distance / render_config.flashlight_range;
+    if off_center >= 1. || far >= 1. { return 0. }
+
+    render_config.flashlight_intensity * (1. - off_center) * (1. - far)
+}
+
+fn apply_depth_desaturation(color: Color, distance: f32, render_config: &RenderConfig) -> Color {
+    let far = (distance / render_config.desaturation_distance).clamp(0., 1.);
+    let [hue, saturation, lightness, alpha] = color.as_hsla_f32();
+    Color::hsla(hue, saturation * (1. - render_config.depth_desaturation * far), lightness, alpha)
 }
 
-#[derive(Component)]
-struct Player {}
+fn darken(color: Color, factor: f32) -> Color {
+    let [r, g, b, a] = color.as_rgba_f32();
+    Color::rgba(r * factor, g * factor, b * factor, a)
+}
+
+fn apply_gamma(color: Color, gamma: f32) -> Color {
+    let [r, g, b, a] = color.as_rgba_f32();
+    Color::rgba(r.powf(1. / gamma), g.powf(1. / gamma), b.powf(1. / gamma), a)
+}
+
+// the fog between the camera and `hit`, none for walls that aren't shaded so they keep their own color
+fn wall_fog(environment: &Environment, hit: &RaycastHit, ray_origin: Vec2, ray_direction: Vec2) -> f32 {
+    if environment.kind(hit.cell).shaded() { environment.fog_along(ray_origin, ray_direction, hit.distance) } else { 0. }
+}
+
+// fades `color` toward the fog color the more fog lies between it and the camera,
+// left alone in ViewMode::Depth so its colors keep meaning distance
+fn apply_fog(color: Color, fog: f32, render_config: &RenderConfig) -> Color {
+    if fog <= 0. || render_config.view_mode == ViewMode::Depth { return color }
+
+    let amount = 1. - (-fog).exp();
+    let [fog_r, fog_g, fog_b, _] = apply_gamma(render_config.fog_color, render_config.gamma).as_rgba_f32();
+    let [r, g, b, a] = color.as_rgba_f32();
+    Color::rgba(r + (fog_r - r) * amount, g + (fog_g - g) * amount, b + (fog_b - b) * amount, a)
+}
+
+// the ray through the center of the screen
+fn look_ray(pose: &Pose) -> (Vec2, Vec2) {
+    (pose.position, Vec2::from_angle(pose.rotation))
+}
+
+// the look ray moved out to the weapon muzzle, so shots line up with the weapon
+fn muzzle_ray(pose: &Pose, weapon_config: &WeaponConfig) -> (Vec2, Vec2) {
+    let (eye, direction) = look_ray(pose);
+    (eye + weapon_config.muzzle_offset.rotate(direction), direction)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fire_weapon(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    enemy_query: Query<(Entity, &Enemy)>,
+    weapon_config: Res<WeaponConfig>,
+    enemy_config: Res<EnemyConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut screen_shake: ResMut<ScreenShake>,
+    mut bursts: EventWriter<ParticleBurst>,
+    mut damage: EventWriter<DamageEvent>,
+    mut shots: EventWriter<ShotFired>,
+    mut impacts: EventWriter<WallImpact>,
+    mut commands: Commands,
+) {
+    if !keyboard.just_pressed(key_bindings.fire) { return }
+
+    shots.send(ShotFired);
+
+    let kick = screen_shake.fire_kick;
+    screen_shake.kick(kick);
+
+    let Ok(player) = player_query.get_single() else { return };
+    let environment = levels.active();
+    let (origin, direction) = muzzle_ray(player, &weapon_config);
+
+    if let Some(speed) = weapon_config.projectile_speed {
+        commands.spawn(Projectile { pos: origin, vel: direction * speed, damage: weapon_config.damage });
+        return;
+    }
+
+    let wall_hit = raycast(environment, origin, direction);
+    let wall_distance = wall_hit.map_or(f32::INFINITY, |hit| hit.distance);
+
+    // the closest enemy in front of the wall takes the shot
+    let enemy_hit = enemy_query.iter()
+        .filter_map(|(entity, enemy)| ray_circle_distance(origin, direction, enemy.position, enemy_config.radius).map(|distance| (entity, distance)))
+        .filter(|&(_, distance)| distance < wall_distance)
+        .min_by(|a, b| a.1.total_cmp(&b.1));
+
+    if let Some((target, _)) = enemy_hit {
+        damage.send(DamageEvent { target, amount: weapon_config.damage });
+    }
+    else if let Some(hit) = wall_hit {
+        // back off a little so the sparks don't start inside the wall
+        bursts.send(ParticleBurst { position: origin + direction * (hit.distance - 0.05), count: 12 });
+        impacts.send(WallImpact { cell: hit.cell });
+    }
+}
+
+// moves each projectile along the segment it covers this frame; whichever comes first along it, a wall cell
+// it enters or an enemy's circle, stops it. ones that leave the level are dropped since nothing is out there
+#[allow(clippy::too_many_arguments)]
+fn step_projectiles(
+    mut projectile_query: Query<(Entity, &mut Projectile)>,
+    enemy_query: Query<(Entity, &Enemy)>,
+    levels: Res<Levels>,
+    enemy_config: Res<EnemyConfig>,
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+    mut bursts: EventWriter<ParticleBurst>,
+    mut damage: EventWriter<DamageEvent>,
+    mut impacts: EventWriter<WallImpact>,
+    mut commands: Commands,
+) {
+    let environment = levels.active();
+    let delta_seconds = time_scale.delta_seconds(&time);
+
+    for (entity, mut projectile) in &mut projectile_query {
+        let travel = projectile.vel.length() * delta_seconds;
+        let Some(direction) = projectile.vel.try_normalize() else { continue };
+        let from = projectile.pos;
+
+        // stopped by the same walls as a hitscan shot; the cell it starts in is skipped, it was either just fired
+        // from there or already checked last frame
+        let wall_hit = raycast(environment, from, direction).filter(|hit| hit.distance <= travel);
+        let wall_distance = wall_hit.map_or(f32::INFINITY, |hit| hit.distance);
+
+        let enemy_hit = enemy_query.iter()
+            .filter_map(|(target, enemy)| ray_circle_distance(from, direction, enemy.position, enemy_config.radius).map(|distance| (target, distance)))
+            .filter(|&(_, distance)| distance <= travel && distance < wall_distance)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
 
-#[derive(Component)]
-struct Transform {
-    position: Vec2,
-    rotation: f32,
+        if let Some((target, _)) = enemy_hit {
+            damage.send(DamageEvent { target, amount: projectile.damage });
+            commands.entity(entity).despawn();
+        }
+        else if let Some(hit) = wall_hit {
+            bursts.send(ParticleBurst { position: from + direction * (hit.distance - 0.05).max(0.), count: 8 });
+            impacts.send(WallImpact { cell: hit.cell });
+            commands.entity(entity).despawn();
+        }
+        else {
+            projectile.pos = from + direction * travel;
+
+            let cell = world_to_cell(projectile.pos);
+            let inside = environment.bounds().is_some_and(|(min, max)| cell.0 >= min.x && cell.1 >= min.y && cell.0 <= max.x && cell.1 <= max.y);
+            if !inside {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
 }
 
-#[derive(Component)]
-struct Environment { walls: Vec<(i32, i32)>}
+fn spawn_particles(
+    particle_query: Query<&Particle>,
+    mut bursts: EventReader<ParticleBurst>,
+    mut rng: ResMut<GameRng>,
+    mut commands: Commands,
+) {
+    let mut alive = particle_query.iter().count();
 
-fn update_player(
-    mut player_query: Query<&mut Transform, With<Player>>,
-    keyboard: Res<ButtonInput<KeyCode>>,
+    for burst in bursts.read() {
+        for i in 0..burst.count {
+            if alive >= MAX_PARTICLES { break }
+
+            // evenly spread with a little jitter so bursts don't all look the same
+            let angle = (i as f32 + rng.0.gen_range(-0.5..0.5)) * TAU / burst.count as f32;
+            let speed: f32 = rng.0.gen_range(1.0..2.0);
+
+            commands.spawn(Particle { pos: burst.position, vel: Vec2::from_angle(angle) * speed, life: PARTICLE_LIFETIME });
+            alive += 1;
+        }
+    }
+}
+
+fn update_particles(
+    mut particle_query: Query<(Entity, &mut Particle)>,
     time: Res<Time>,
+    time_scale: Res<TimeScale>,
+    mut commands: Commands,
 ) {
-    let mut transform = player_query.get_single_mut().expect("p");
+    let delta_seconds = time_scale.delta_seconds(&time);
+    for (entity, mut particle) in &mut particle_query {
+        particle.life -= delta_seconds;
 
-    let mut direction = Vec2::ZERO;
-    
-    if keyboard.pressed(KeyCode::ArrowLeft)  {
-        transform.rotation += PLAYER_TURNING_SPEED * time.delta_seconds();
+        if particle.life <= 0.0 {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let velocity = particle.vel;
+        particle.pos += velocity * delta_seconds;
     }
-    if keyboard.pressed(KeyCode::ArrowRight)  {
-        transform.rotation -= PLAYER_TURNING_SPEED * time.delta_seconds();
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_particles(
+    window_query: Query<&Window>,
+    viewer_query: Query<(&Viewer, &Pose, &CameraRoll, &DepthBuffer)>,
+    particle_query: Query<&Particle>,
+    render_config: Res<RenderConfig>,
+    screen_shake: Res<ScreenShake>,
+    head_bob: Res<HeadBob>,
+    mut gizmos: Gizmos,
+) {
+    if render_config.view_mode == ViewMode::Ascii { return }
+
+    let scale = 100.;
+
+    let Ok(window) = window_query.get_single() else { return };
+
+    for (&Viewer { slot }, player, roll, depth_buffer) in &viewer_query {
+        if render_config.top_down() && slot > 0 { continue }
+
+        let viewport = Viewport::new(window, &render_config, slot);
+        let view = ViewTransform::new(roll, &screen_shake, head_bob.offset_for(slot), viewport, &render_config);
+        let vertical_scale = render_config.camera_model.focal_length(viewport.width as u32, render_config.fov);
+
+        for particle in &particle_query {
+            let color = Color::hsl(40., 1., 0.6).with_a(particle.life / PARTICLE_LIFETIME);
+
+            if render_config.top_down() {
+                gizmos.circle_2d(particle.pos * scale, PARTICLE_SIZE * scale, color);
+                continue;
+            }
+
+            let Some(projected) = project_point(player, particle.pos, viewport.width, &render_config) else { continue };
+            if projected.column < 0.0 || projected.column >= viewport.width { continue }
+
+            let occluded = depth_buffer.distances.get(projected.column as usize).is_some_and(|&wall| wall < projected.depth);
+            if occluded { continue }
+
+            gizmos.circle_2d(view.apply(Vec2::new(viewport.width / 2. - projected.column, 0.)), vertical_scale * PARTICLE_SIZE / projected.size_depth * viewport.scale.min_element(), view.quantize(color));
+        }
     }
+}
+
+struct ProjectedPoint {
+    // may lie outside the screen for points just beyond the edge of the view
+    column: f32,
+    // distance to compare against the depth buffer
+    depth: f32,
+    // distance that sets the size on screen, scaled the same way as walls
+    size_depth: f32,
+}
 
-    transform.rotation = transform.rotation % TAU;
+// where a world point shows up in the first-person view, None when it's behind the viewer
+fn project_point(viewer: &Pose, point: Vec2, render_width: f32, render_config: &RenderConfig) -> Option<ProjectedPoint> {
+    // x is the distance in front of the viewer, y is to the left
+    let local = (point - viewer.position).rotate(Vec2::from_angle(-viewer.rotation));
+    if local.x <= 0.0 { return None }
 
-    if keyboard.pressed(KeyCode::KeyW)  {
-        direction += Vec2::new(1.0, 0.0);
+    let (column, depth, size_depth) = match render_config.projection {
+        ProjectionMode::Perspective => {
+            let angle = local.y.atan2(local.x);
+            let column = render_config.camera_model.angle_to_column(angle, render_width as u32, render_config.fov);
+            (column, local.length(), render_config.camera_model.projected_depth(local.length(), angle))
+        }
+        ProjectionMode::Orthographic => (local.y / render_config.orthographic_width * render_width + render_width / 2., local.x, local.x),
+    };
+
+    Some(ProjectedPoint { column, depth, size_depth })
+}
+
+// the cells enemies start in, at most EnemyConfig::count of them
+fn enemy_cells(rng: &mut GameRng, environment: &Environment, player_cell: (i32, i32), enemy_config: &EnemyConfig) -> Vec<(i32, i32)> {
+    match &enemy_config.spawn_cells {
+        Some(cells) => {
+            let usable = cells.iter().copied().filter(|&cell| !environment.blocks_movement(cell) && cell != player_cell);
+            usable.take(enemy_config.count).collect()
+        }
+        None => {
+            let open: Vec<(i32, i32)> = open_cells(environment, player_cell).into_iter().filter(|&cell| cell != player_cell).collect();
+            open.choose_multiple(&mut rng.0, enemy_config.count).copied().collect()
+        }
     }
-    if keyboard.pressed(KeyCode::KeyA)  {
-        direction += Vec2::new(0.0, 1.0);
+}
+
+fn spawn_enemies(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    enemy_config: Res<EnemyConfig>,
+    mut rng: ResMut<GameRng>,
+    mut commands: Commands,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+    place_enemies(&mut commands, &mut rng, levels.active(), world_to_cell(player.position), &enemy_config);
+}
+
+fn spawn_items(levels: Res<Levels>, mut commands: Commands) {
+    place_items(&mut commands, &levels.levels[levels.active]);
+}
+
+fn place_items(commands: &mut Commands, level: &Level) {
+    for &(cell, kind) in &level.items {
+        commands.spawn(Item { kind, cell });
     }
-    if keyboard.pressed(KeyCode::KeyS)  {
-        direction += Vec2::new(-1.0, 0.0);
+}
+
+fn collect_items(
+    player_query: Query<&Pose, With<Player>>,
+    item_query: Query<(Entity, &Item)>,
+    mut levels: ResMut<Levels>,
+    mut inventory: ResMut<Inventory>,
+    mut pickups: EventWriter<ItemPickedUp>,
+    mut commands: Commands,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+    let cell = world_to_cell(player.position);
+
+    for (entity, item) in &item_query {
+        if item.cell != cell { continue }
+
+        *inventory.counts.entry(item.kind).or_default() += 1;
+        let active = levels.active;
+        levels.levels[active].items.retain(|&(at, _)| at != item.cell);
+        commands.entity(entity).despawn();
+
+        info!("picked up {:?}, now holding {}", item.kind, inventory.counts[&item.kind]);
+        pickups.send(ItemPickedUp);
     }
-    if keyboard.pressed(KeyCode::KeyD)  {
-        direction += Vec2::new(0.0, -1.0);
+}
+
+fn place_enemies(commands: &mut Commands, rng: &mut GameRng, environment: &Environment, player_cell: (i32, i32), enemy_config: &EnemyConfig) {
+    for cell in enemy_cells(rng, environment, player_cell, enemy_config) {
+        commands.spawn((
+            Enemy { position: Vec2::new(cell.0 as f32, cell.1 as f32) + Vec2::splat(0.5), facing: rng.0.gen_range(0.0..TAU) },
+            Health { hp: enemy_config.health },
+            Blocking { radius: enemy_config.radius },
+        ));
     }
+}
 
-    if direction.length() > 0.0 {
-        direction = direction.normalize();
-        let player_direction = Vec2::from_angle(transform.rotation);
-        transform.position += direction.rotate(player_direction) * PLAYER_SPEED * time.delta_seconds();
+fn apply_damage(
+    mut enemy_query: Query<(&Enemy, &mut Health)>,
+    mut damage: EventReader<DamageEvent>,
+    mut bursts: EventWriter<ParticleBurst>,
+    mut commands: Commands,
+) {
+    for event in damage.read() {
+        let Ok((enemy, mut health)) = enemy_query.get_mut(event.target) else { continue };
+
+        // already killed by an earlier event this frame
+        if health.hp <= 0. { continue }
+
+        health.hp -= event.amount;
+
+        if health.hp <= 0. {
+            commands.entity(event.target).despawn();
+            bursts.send(ParticleBurst { position: enemy.position, count: 24 });
+        }
     }
 }
 
-fn raycast(
-    walls: &Vec<(i32, i32)>,
-    start_pos: Vec2,
-    direction: Vec2,
-) -> Option<f32> {
-    let mut current_cell = (start_pos.x.floor() as i32, start_pos.y.floor() as i32);
+#[allow(clippy::too_many_arguments)]
+fn draw_crosshair(
+    window_query: Query<&Window>,
+    player_query: Query<Entity, With<Player>>,
+    render_config: Res<RenderConfig>,
+    crosshair_config: Res<CrosshairConfig>,
+    time: Res<Time>,
+    mut damage: EventReader<DamageEvent>,
+    mut last_hit: Local<Option<f32>>,
+    mut gizmos: Gizmos,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+
+    // the shot's target may already be despawned, so anything but the player counts as a hit
+    for event in damage.read() {
+        if event.target != player {
+            *last_hit = Some(time.elapsed_seconds());
+        }
+    }
+
+    if !render_config.first_person() { return }
+
+    let Ok(window) = window_query.get_single() else { return };
+    // in the middle of the first player's view, which is only the window's middle without split screen
+    let center = Viewport::new(window, &render_config, 0).center;
+    let gap = crosshair_config.gap;
+    let size = crosshair_config.size;
+
+    for arm in [Vec2::X, Vec2::Y, Vec2::NEG_X, Vec2::NEG_Y] {
+        gizmos.line_2d(center + arm * gap, center + arm * (gap + size), crosshair_config.color);
+    }
+
+    let Some(hit_time) = *last_hit else { return };
+    let progress = (time.elapsed_seconds() - hit_time) / crosshair_config.hit_duration;
+    if progress >= 1. {
+        *last_hit = None;
+        return;
+    }
+
+    // diagonal arms that drift outward and fade over the marker's lifetime
+    let offset = gap + progress * crosshair_config.hit_expand;
+    let color = crosshair_config.hit_color.with_a(crosshair_config.hit_color.a() * (1. - progress));
 
-    if direction.x == 0.0 || direction.y == 0.0 {
-        let mut ray_length = start_pos.fract().dot(direction);
+    for arm in [Vec2::new(1., 1.), Vec2::new(-1., 1.), Vec2::new(-1., -1.), Vec2::new(1., -1.)] {
+        let arm = arm.normalize();
+        gizmos.line_2d(center + arm * offset, center + arm * (offset + size), color);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn switch_level(
+    mut player_query: Query<&mut Pose, With<Player>>,
+    mut second_query: Query<&mut Pose, (With<SecondPlayer>, Without<Player>)>,
+    enemy_query: Query<Entity, With<Enemy>>,
+    item_query: Query<Entity, With<Item>>,
+    particle_query: Query<Entity, With<Particle>>,
+    mut levels: ResMut<Levels>,
+    mut grid_step: ResMut<GridStep>,
+    enemy_config: Res<EnemyConfig>,
+    key_bindings: Res<KeyBindings>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut requests: EventReader<SwitchLevel>,
+    mut rng: ResMut<GameRng>,
+    mut commands: Commands,
+) {
+    let mut target = requests.read().last().map(|request| request.0);
+    if keyboard.just_pressed(key_bindings.next_level) {
+        target = Some(levels.active + 1);
+    }
 
-        if ray_length < 0.0 { ray_length = ray_length.abs() }
-        else                { ray_length = 1.0 - ray_length }
+    let Some(target) = target else { return };
+    let target = target % levels.levels.len();
+    if target == levels.active { return }
 
-        for _ in 1..RAYCAST_DEPTH {
-            current_cell.0 += direction.x as i32;
-            current_cell.1 += direction.y as i32;
+    levels.active = target;
+    // a grid step in progress would carry on between the old level's cells
+    grid_step.tween = None;
 
-            if walls.contains(&current_cell) { return Some(ray_length) }
+    // the level switches even while the player entity is missing, enemies then gather around the spawn point
+    let mut player_position = levels.levels[target].spawn_position;
+    if let Ok(mut pose) = player_query.get_single_mut() {
+        if !levels.preserve_player {
+            pose.position = levels.levels[target].spawn_position;
+            pose.rotation = levels.levels[target].spawn_rotation;
+        }
 
-            ray_length += 1.0;
+        pose.position = unstick(levels.active(), pose.position);
+        player_position = pose.position;
+    }
+    if let Ok(mut pose) = second_query.get_single_mut() {
+        if !levels.preserve_player {
+            pose.position = levels.levels[target].spawn_position;
+            pose.rotation = levels.levels[target].spawn_rotation + PI;
         }
-        return None;
+        pose.position = unstick(levels.active(), pose.position);
+    }
+
+    // enemies, items and particles belong to the level they were spawned in
+    for entity in enemy_query.iter().chain(&item_query) {
+        commands.entity(entity).despawn();
+    }
+    for entity in &particle_query {
+        commands.entity(entity).despawn();
+    }
+    place_enemies(&mut commands, &mut rng, levels.active(), world_to_cell(player_position), &enemy_config);
+    place_items(&mut commands, &levels.levels[target]);
+}
+
+// a kept position or a badly placed spawn can end up inside a wall of the new level, this moves it to the
+// middle of the nearest open cell
+fn unstick(environment: &Environment, position: Vec2) -> Vec2 {
+    if !environment.blocks_movement(world_to_cell(position)) { return position }
+
+    match nearest_open_cell(environment, position) {
+        Some(cell) => Vec2::new(cell.0 as f32, cell.1 as f32) + Vec2::splat(0.5),
+        None => position,
+    }
+}
+
+// enemies walk toward the player while they can see them and stay put otherwise
+fn chase_player(
+    player_query: Query<&Pose, With<Player>>,
+    levels: Res<Levels>,
+    mut enemy_query: Query<&mut Enemy>,
+    enemy_config: Res<EnemyConfig>,
+    movement_config: Res<MovementConfig>,
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+) {
+    let Ok(player) = player_query.get_single() else { return };
+    let environment = levels.active();
+
+    for mut enemy in &mut enemy_query {
+        let offset = player.position - enemy.position;
+        if offset.length() <= enemy_config.chase_distance { continue }
+        if !has_line_of_sight(environment, enemy.position, player.position) { continue }
+
+        let delta = offset.normalize() * enemy_config.speed * time_scale.delta_seconds(&time);
+        enemy.facing = offset.y.atan2(offset.x);
+        enemy.position = move_with_collision(environment, &movement_config, enemy.position, delta);
     }
+}
+
+// the level from above in a box in one corner of the window, everything outside the box is cut off
+fn draw_minimap(
+    window_query: Query<&Window>,
+    player_query: Query<&Pose, With<Player>>,
+    enemy_query: Query<&Enemy>,
+    levels: Res<Levels>,
+    minimap_config: Res<MinimapConfig>,
+    render_config: Res<RenderConfig>,
+    mut gizmos: Gizmos,
+) {
+    if render_config.top_down() || !minimap_config.enabled { return }
 
-    let x_intercept = |x: i32| -> f32 {
-        let mut a = x as f32 - start_pos.fract().x;
-        if direction.x < 0.0 { a += 1.0 }
-        a / direction.x
+    let Ok(window) = window_query.get_single() else { return };
+    let Ok(player) = player_query.get_single() else { return };
+    let environment = levels.active();
+
+    let half_window = Vec2::new(window.resolution.width(), window.resolution.height()) / 2.;
+    let size = minimap_config.size.min(half_window.x * 2. - minimap_config.margin * 2.).min(half_window.y * 2. - minimap_config.margin * 2.);
+    if size <= 0. { return }
+
+    let inset = half_window - Vec2::splat(minimap_config.margin + size / 2.);
+    let box_center = match minimap_config.corner {
+        ScreenCorner::TopLeft => Vec2::new(-inset.x, inset.y),
+        ScreenCorner::TopRight => inset,
+        ScreenCorner::BottomLeft => -inset,
+        ScreenCorner::BottomRight => Vec2::new(inset.x, -inset.y),
     };
+    let (box_min, box_max) = (box_center - Vec2::splat(size / 2.), box_center + Vec2::splat(size / 2.));
 
-    let y_intercept = |y: i32| -> f32 {
-        let mut a = y as f32 - start_pos.fract().y;
-        if direction.y < 0.0 { a += 1.0 }
-        a / direction.y
+    // world position at the box center and pixels per world unit
+    let (world_center, scale) = match minimap_config.zoom {
+        MinimapZoom::Fit => match environment.bounds() {
+            Some((min, max)) => {
+                let (min, max) = (min.as_vec2(), max.as_vec2() + Vec2::ONE);
+                ((min + max) / 2., size / (max - min).max_element())
+            }
+            None => (player.position, size),
+        },
+        MinimapZoom::Follow { cells } => (player.position, size / cells.max(1.)),
     };
+    let to_screen = |point: Vec2| box_center + (point - world_center) * scale;
 
-    let step_direction = (direction.signum().x as i32, direction.signum().y as i32);
-    let mut distance: f32;
+    // gizmos can't fill shapes, so the background is one line per pixel row, same as DEBUG_MAP_FILLED_WALLS
+    for row in 0..size as i32 {
+        let y = box_min.y + row as f32 + 0.5;
+        gizmos.line_2d(Vec2::new(box_min.x, y), Vec2::new(box_max.x, y), minimap_config.background);
+    }
 
-    for _ in 1..RAYCAST_DEPTH {
-        let steps_taken = (current_cell.0 - start_pos.x.floor() as i32, current_cell.1 - start_pos.y.floor() as i32);
+    for (x, y) in environment.iter_walls() {
+        let min = to_screen(Vec2::new(x as f32, y as f32));
+        let Some((min, max)) = clip_rect(min, min + Vec2::splat(scale), box_min, box_max) else { continue };
+        gizmos.rect_2d((min + max) / 2., 0., max - min, minimap_config.walls);
+    }
 
-        let x_intercept_distance = x_intercept(steps_taken.0 + step_direction.0);
-        let y_intercept_distance = y_intercept(steps_taken.1 + step_direction.1);
+    for enemy in &enemy_query {
+        let position = to_screen(enemy.position);
+        if position.cmpge(box_min).all() && position.cmple(box_max).all() {
+            gizmos.circle_2d(position, 2., minimap_config.enemies);
+        }
+    }
 
-        if x_intercept_distance < y_intercept_distance {
-            current_cell.0 += step_direction.0;
-            distance = x_intercept_distance;
+    if let Some(color) = minimap_config.player_cell {
+        let (x, y) = world_to_cell(player.position);
+        let min = to_screen(Vec2::new(x as f32, y as f32));
+        if let Some((min, max)) = clip_rect(min, min + Vec2::splat(scale), box_min, box_max) {
+            gizmos.rect_2d((min + max) / 2., 0., max - min, color);
         }
-        else {
-            current_cell.1 += step_direction.1;
-            distance = y_intercept_distance;
+    }
+
+    let position = to_screen(player.position);
+    let facing = position + Vec2::from_angle(player.rotation) * (scale / 2.).max(6.);
+    if let Some((from, to)) = clip_line(position, facing, box_min, box_max) {
+        gizmos.line_2d(from, to, minimap_config.player);
+    }
+
+    gizmos.rect_2d(box_center, 0., Vec2::splat(size), minimap_config.border);
+}
+
+// the part of the rect from `min` to `max` inside the box from `clip_min` to `clip_max`, None if nothing is left
+fn clip_rect(min: Vec2, max: Vec2, clip_min: Vec2, clip_max: Vec2) -> Option<(Vec2, Vec2)> {
+    let (min, max) = (min.max(clip_min), max.min(clip_max));
+    (min.x < max.x && min.y < max.y).then_some((min, max))
+}
+
+// the part of the segment from `from` to `to` inside the box from `min` to `max`, None if it misses the box
+fn clip_line(from: Vec2, to: Vec2, min: Vec2, max: Vec2) -> Option<(Vec2, Vec2)> {
+    let delta = to - from;
+    let (mut enter, mut exit) = (0f32, 1f32);
+
+    for (toward, room) in [(-delta.x, from.x - min.x), (delta.x, max.x - from.x), (-delta.y, from.y - min.y), (delta.y, max.y - from.y)] {
+        if toward == 0. {
+            if room < 0. { return None }
+            continue;
         }
 
-        if walls.contains(&current_cell) {
-            return Some(distance);
+        let t = room / toward;
+        if toward < 0. { enter = enter.max(t) } else { exit = exit.min(t) }
+    }
+
+    (enter <= exit).then(|| (from + delta * enter, from + delta * exit))
+}
+
+const SPRITE_FRAME_SHADES: [f32; 8] = [1., 0.85, 0.7, 0.55, 0.4, 0.55, 0.7, 0.85];
+
+// which of eight sprite frames shows an entity facing `facing` seen from `viewer`: 0 when it looks straight
+// at the viewer, counting up counterclockwise so 4 is its back
+fn sprite_frame(viewer: Vec2, position: Vec2, facing: f32) -> usize {
+    let to_viewer = viewer - position;
+    let relative = (to_viewer.y.atan2(to_viewer.x) - facing).rem_euclid(TAU);
+
+    // each frame covers the 45 degrees centered on its direction, so frame 0 wraps around zero
+    ((relative + PI / 8.) / (PI / 4.)) as usize % 8
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_enemies(
+    window_query: Query<&Window>,
+    viewer_query: Query<(&Viewer, &Pose, &Elevation, &CameraRoll, &DepthBuffer)>,
+    enemy_query: Query<&Enemy>,
+    render_config: Res<RenderConfig>,
+    enemy_config: Res<EnemyConfig>,
+    screen_shake: Res<ScreenShake>,
+    head_bob: Res<HeadBob>,
+    debug_colors: Res<DebugColors>,
+    mut gizmos: Gizmos,
+) {
+    if render_config.view_mode == ViewMode::Ascii { return }
+
+    let scale = 100.;
+
+    let Ok(window) = window_query.get_single() else { return };
+
+    for (&Viewer { slot }, player, elevation, roll, depth_buffer) in &viewer_query {
+        if render_config.top_down() && slot > 0 { continue }
+
+        let viewport = Viewport::new(window, &render_config, slot);
+        let view = ViewTransform::new(roll, &screen_shake, head_bob.offset_for(slot), viewport, &render_config);
+        let vertical_scale = render_config.camera_model.focal_length(viewport.width as u32, render_config.fov);
+        let eye_height = render_config.eye_height + elevation.height;
+
+        for enemy in &enemy_query {
+            if render_config.top_down() {
+                gizmos.circle_2d(enemy.position * scale, enemy_config.radius * scale, debug_colors.enemies);
+                continue;
+            }
+
+            let Some(projected) = project_point(player, enemy.position, viewport.width, &render_config) else { continue };
+
+            let unit = vertical_scale / projected.size_depth;
+            let (floor, _) = wall_span(unit, 1., eye_height, render_config.anchor);
+            // no sprite sheets to pick from yet, so each of the eight frames is a shade, lightest from the front
+            let frame = sprite_frame(player.position, enemy.position, enemy.facing);
+            let color = Color::hsl(0., 0.8, (1.5 / projected.depth).min(0.5) * SPRITE_FRAME_SHADES[frame]);
+            let color = apply_gamma(apply_depth_desaturation(color, projected.depth, &render_config), render_config.gamma);
+
+            draw_sprite(&mut gizmos, &view, depth_buffer, &projected, unit * enemy_config.radius, (floor, floor + unit * ENEMY_HEIGHT), color);
         }
     }
+}
 
-    None
+#[allow(clippy::too_many_arguments)]
+fn draw_items(
+    window_query: Query<&Window>,
+    viewer_query: Query<(&Viewer, &Pose, &Elevation, &CameraRoll, &DepthBuffer)>,
+    item_query: Query<&Item>,
+    render_config: Res<RenderConfig>,
+    screen_shake: Res<ScreenShake>,
+    head_bob: Res<HeadBob>,
+    mut gizmos: Gizmos,
+) {
+    if render_config.view_mode == ViewMode::Ascii { return }
+
+    let scale = 100.;
+
+    let Ok(window) = window_query.get_single() else { return };
+
+    for (&Viewer { slot }, player, elevation, roll, depth_buffer) in &viewer_query {
+        if render_config.top_down() && slot > 0 { continue }
+
+        let viewport = Viewport::new(window, &render_config, slot);
+        let view = ViewTransform::new(roll, &screen_shake, head_bob.offset_for(slot), viewport, &render_config);
+        let vertical_scale = render_config.camera_model.focal_length(viewport.width as u32, render_config.fov);
+        let eye_height = render_config.eye_height + elevation.height;
+
+        for item in &item_query {
+            let position = Vec2::new(item.cell.0 as f32, item.cell.1 as f32) + Vec2::splat(0.5);
+
+            if render_config.top_down() {
+                gizmos.rect_2d(position * scale, 0., Vec2::splat(ITEM_SIZE * scale), item.kind.color());
+                continue;
+            }
+
+            let Some(projected) = project_point(player, position, viewport.width, &render_config) else { continue };
+
+            let unit = vertical_scale / projected.size_depth;
+            let (floor, _) = wall_span(unit, 1., eye_height, render_config.anchor);
+            let color = darken(item.kind.color(), (1.5 / projected.depth).min(1.));
+            let color = apply_gamma(apply_depth_desaturation(color, projected.depth, &render_config), render_config.gamma);
+
+            draw_sprite(&mut gizmos, &view, depth_buffer, &projected, unit * ITEM_SIZE / 2., (floor, floor + unit * ITEM_SIZE), color);
+        }
+    }
 }
 
-fn draw_scene(
+#[allow(clippy::too_many_arguments)]
+fn draw_projectiles(
     window_query: Query<&Window>,
-    player_query: Query<&Transform, With<Player>>,
-    walls_query: Query<&Environment>,
+    viewer_query: Query<(&Viewer, &Pose, &CameraRoll, &DepthBuffer)>,
+    projectile_query: Query<&Projectile>,
+    render_config: Res<RenderConfig>,
+    screen_shake: Res<ScreenShake>,
+    head_bob: Res<HeadBob>,
     mut gizmos: Gizmos,
 ) {
+    if render_config.view_mode == ViewMode::Ascii { return }
+
     let scale = 100.;
+    let color = Color::hsl(50., 1., 0.7);
+
+    let Ok(window) = window_query.get_single() else { return };
+
+    for (&Viewer { slot }, player, roll, depth_buffer) in &viewer_query {
+        if render_config.top_down() && slot > 0 { continue }
+
+        let viewport = Viewport::new(window, &render_config, slot);
+        let view = ViewTransform::new(roll, &screen_shake, head_bob.offset_for(slot), viewport, &render_config);
+        let vertical_scale = render_config.camera_model.focal_length(viewport.width as u32, render_config.fov);
+
+        for projectile in &projectile_query {
+            if render_config.top_down() {
+                gizmos.circle_2d(projectile.pos * scale, PROJECTILE_SIZE / 2. * scale, color);
+                continue;
+            }
+
+            let Some(projected) = project_point(player, projectile.pos, viewport.width, &render_config) else { continue };
+
+            // flies at eye height, so it's centered on the horizon
+            let half_size = vertical_scale / projected.size_depth * PROJECTILE_SIZE / 2.;
+            let color = apply_gamma(apply_depth_desaturation(color, projected.depth, &render_config), render_config.gamma);
+
+            draw_sprite(&mut gizmos, &view, depth_buffer, &projected, half_size, (-half_size, half_size), color);
+        }
+    }
+}
+
+// a flat sprite `half_width` pixels to either side of its projected center and spanning `span` vertically,
+// drawn column by column so walls in front hide the right parts of it
+fn draw_sprite(
+    gizmos: &mut Gizmos,
+    view: &ViewTransform,
+    depth_buffer: &DepthBuffer,
+    projected: &ProjectedPoint,
+    half_width: f32,
+    (bottom, top): (f32, f32),
+    color: Color,
+) {
+    let width = view.viewport.width;
+    let left = (projected.column - half_width).max(0.) as i32;
+    let right = (projected.column + half_width).min(width - 1.) as i32;
+
+    for column in left..=right {
+        let occluded = depth_buffer.distances.get(column as usize).is_some_and(|&wall| wall < projected.depth);
+        if occluded { continue }
 
-    let window = window_query.get_single().unwrap();
-    let player = player_query.get_single().unwrap();
-    let environment = walls_query.get_single().unwrap();
+        let x = width / 2. - column as f32;
+        view.column_line(gizmos, x, bottom, top, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_along_a_wall_keeps_the_tangential_speed() {
+        let environment = Environment::new((-5..5).map(|y| (2, y)));
+        // touching the wall and straddling the cells (2, 0) and (2, 1) on the way up
+        let start = Vec2::new(2. - PLAYER_RADIUS, 0.8);
+        let (velocity, delta_time) = (Vec2::new(1., 1.), 0.1);
+
+        let end = move_with_collision(&environment, &MovementConfig::default(), start, velocity * delta_time);
+        let after = (end - start) / delta_time;
+
+        assert!(after.x.abs() < 1e-4, "moved into the wall: {after:?}");
+        assert!((after.y - velocity.y).abs() < 1e-4, "lost tangential speed: {after:?}");
+    }
+
+    #[test]
+    fn depth_desaturation_fades_far_walls_to_grey() {
+        let render_config = RenderConfig { depth_desaturation: 1., ..default() };
+        let red = Color::hsl(0., 1., 0.5);
+
+        let near = apply_depth_desaturation(red, 0., &render_config).as_hsla_f32();
+        assert!((near[1] - 1.).abs() < 1e-4, "{near:?}");
+
+        let far = apply_depth_desaturation(red, render_config.desaturation_distance * 0.99, &render_config).as_hsla_f32();
+        assert!(far[1] < 0.05, "{far:?}");
+        assert!((far[2] - 0.5).abs() < 1e-4, "lightness changed: {far:?}");
+    }
+
+    #[test]
+    fn overlapping_circles_end_up_just_touching() {
+        let other = Vec2::new(1., 1.);
+        let separated = separate_circles(Vec2::new(1.3, 1.), 0.2, other, 0.3);
+        assert!((separated.distance(other) - 0.5).abs() < 1e-5, "{separated:?}");
+        // pushed straight away from the other circle, not sideways
+        assert!((separated.y - 1.).abs() < 1e-5 && separated.x > 1.3, "{separated:?}");
+
+        let touching = Vec2::new(1.5, 1.);
+        assert_eq!(separate_circles(touching, 0.2, other, 0.3), touching);
+
+        let on_top = separate_circles(other, 0.2, other, 0.3);
+        assert!((on_top.distance(other) - 0.5).abs() < 1e-5, "{on_top:?}");
+    }
+
+    #[test]
+    fn rounded_corners_carry_the_player_around_smoothly() {
+        // a wall sticking up to y = 1, approached diagonally while sliding up its west face
+        let environment = Environment::new((-3..=0).map(|y| (2, y)));
+        let step = Vec2::new(1., 1.).normalize() * 0.05;
+
+        // largest change of direction between two steps, shortest step, and where the player ends up
+        let slide = |corner_radius: f32| {
+            let movement_config = MovementConfig { corner_radius, ..default() };
+            let mut position = Vec2::new(2. - PLAYER_RADIUS, -1.5);
+            let (mut previous, mut sharpest, mut shortest) = (None::<Vec2>, 0_f32, f32::INFINITY);
+
+            for _ in 0..80 {
+                let next = move_with_collision(&environment, &movement_config, position, step);
+                let moved = next - position;
+                if let Some(previous) = previous { sharpest = sharpest.max(previous.angle_between(moved).abs()) }
+                shortest = shortest.min(moved.length());
+                (previous, position) = (Some(moved), next);
+            }
+
+            (sharpest, shortest, position)
+        };
+
+        let (square_turn, _, _) = slide(0.);
+        let (rounded_turn, shortest, end) = slide(0.25);
+
+        assert!(rounded_turn < square_turn / 2., "{rounded_turn} vs {square_turn}");
+        // never slowed below the speed along the wall, so it didn't snag
+        assert!(shortest >= step.y - 1e-4, "{shortest}");
+        assert!(end.x > 2. && end.y > 1., "didn't get around the corner: {end:?}");
+    }
+
+    #[test]
+    fn switching_levels_while_the_player_is_missing_doesnt_panic() {
+        use bevy::ecs::system::RunSystemOnce;
+
+        let level = |wall| Level {
+            environment: Environment::new([wall]),
+            spawn_position: Vec2::splat(0.5),
+            spawn_rotation: 0.,
+            border: Some(WallKind::Solid),
+            items: Vec::new(),
+            sky: None,
+            ambient: None,
+        };
+
+        let mut world = World::new();
+        world.insert_resource(Levels { levels: vec![level((2, 2)), level((-2, 2))], active: 0, preserve_player: false });
+        world.insert_resource(GameRng(StdRng::seed_from_u64(0)));
+        world.init_resource::<EnemyConfig>();
+        world.init_resource::<MovementConfig>();
+        world.init_resource::<RenderConfig>();
+        world.init_resource::<CameraRollConfig>();
+        world.init_resource::<KeyBindings>();
+        world.init_resource::<CursorCapture>();
+        world.init_resource::<TimeScale>();
+        world.init_resource::<GridStep>();
+        world.init_resource::<HeadBob>();
+        world.init_resource::<Walked>();
+        world.init_resource::<Time>();
+        world.init_resource::<ButtonInput<KeyCode>>();
+        world.init_resource::<Events<SwitchLevel>>();
+        world.init_resource::<Events<MouseMotion>>();
+
+        // the frame the level switches in has no player, as between despawning the old one and spawning the next
+        world.resource_mut::<Events<SwitchLevel>>().send(SwitchLevel(1));
+        world.run_system_once(switch_level);
+        world.run_system_once(update_player);
+        world.run_system_once(track_walked);
+        world.run_system_once(update_head_bob);
+        world.run_system_once(collide_with_entities);
+        world.run_system_once(mouse_look);
+        assert_eq!(world.resource::<Levels>().active, 1);
+
+        // a switch made in the middle of a grid step ends the step, the next frame doesn't pull the player back
+        // toward the old level's cells
+        world.resource_mut::<GridStep>().tween = Some((Vec2::splat(0.5), 0., Vec2::new(1.5, 0.5), 0.));
+        world.resource_mut::<Events<SwitchLevel>>().send(SwitchLevel(0));
+        world.run_system_once(switch_level);
+        assert_eq!(world.resource::<Levels>().active, 0);
+        assert!(world.resource::<GridStep>().tween.is_none());
+
+        // and the next frame carries on with the player back
+        world.spawn((Player{}, Pose{position: Vec2::splat(0.5), rotation: 0.}, Elevation{height: 0.}, CameraRoll{angle: 0.}));
+        world.run_system_once(update_player);
+        world.run_system_once(track_walked);
+        world.run_system_once(update_head_bob);
+        world.run_system_once(collide_with_entities);
+        world.run_system_once(mouse_look);
+    }
+
+    #[test]
+    fn emissive_walls_look_the_same_near_and_far() {
+        let mut environment = Environment::new([(0, 0)]);
+        let render_config = RenderConfig { depth_desaturation: 1., ..default() };
+        let hit_at = |distance| RaycastHit {
+            cell: (0, 0),
+            distance,
+            point: Vec2::ZERO,
+            local: Vec2::ZERO,
+            face: raycast_test::FaceAxis::X,
+            normal: Normal::West,
+        };
+        let colors = |environment: &Environment| [0.5, 4., 40.].map(|distance| hit_color(environment, &hit_at(distance), 0., 0., &render_config));
+
+        let [near, middle, far] = colors(&environment);
+        assert!(near != middle && middle != far, "solid walls should fade");
 
-    let resolution = &window.resolution;
+        environment.insert_wall((0, 0), WallKind::Emissive);
+        environment.wall_colors.insert((0, 0), Color::rgb(1., 0.35, 0.05));
+        let [near, middle, far] = colors(&environment);
+        assert_eq!(near, middle);
+        assert_eq!(middle, far);
+    }
+
+    // where walk takes a player standing at (0.5, 0.5) facing +x in 0.1 seconds with `keys` held
+    fn walk_from_start(movement_config: &MovementConfig, keys: &[KeyCode]) -> Pose {
+        let environment = Environment::new([(9, 9)]);
+        let mut keyboard = ButtonInput::<KeyCode>::default();
+        for &key in keys { keyboard.press(key) }
+
+        let mut pose = Pose { position: Vec2::splat(0.5), rotation: 0. };
+        walk(&mut pose, &environment, movement_config, KeyBindings::default().walk_keys(), &keyboard, 0.1);
+        pose
+    }
+
+    #[test]
+    fn input_inside_the_dead_zones_is_ignored() {
+        assert_eq!(dead_zone(0.4, 0.5), 0.);
+        assert_eq!(dead_zone(-0.4, 0.5), 0.);
+        // input past the dead zone comes through whole, so real movement doesn't lag behind
+        assert_eq!(dead_zone(0.6, 0.5), 0.6);
+
+        let keys = KeyBindings::default();
+        // held keys add up to a length of 1, or 1.4 diagonally
+        let wide = MovementConfig { move_dead_zone: 1.5, ..default() };
+        assert_eq!(walk_from_start(&wide, &[keys.forward, keys.strafe_left]).position, Vec2::splat(0.5));
+
+        let narrow = MovementConfig { move_dead_zone: 0.5, ..default() };
+        let moved = walk_from_start(&narrow, &[keys.forward]).position - Vec2::splat(0.5);
+        assert!((moved.x - narrow.speed * 0.1).abs() < 1e-5 && moved.y.abs() < 1e-5, "{moved:?}");
+    }
+
+    #[test]
+    fn ambient_is_a_floor_under_baked_light_with_the_flashlight_on_top() {
+        let lightness = |color: Color| color.as_hsla_f32()[2];
+        let render_config = RenderConfig { flashlight: false, ..default() };
+
+        // distance falloff and baked brightness first, ambient only lifts what ends up darker than itself
+        assert!(lightness(shade_wall(30., 0., 1., 0., &render_config)) < 0.2);
+        assert!((lightness(shade_wall(30., 0., 1., 0.25, &render_config)) - 0.25).abs() < 1e-4);
+        assert!((lightness(shade_wall(2., 0., 0.05, 0.25, &render_config)) - 0.25).abs() < 1e-4);
+        assert!((lightness(shade_wall(2., 0., 0.5, 0.25, &render_config)) - 0.75).abs() < 1e-4);
+
+        // the flashlight adds to the result, so a lit wall in a bright level is brighter than ambient alone
+        let lit = RenderConfig { flashlight: true, ..default() };
+        let boost = flashlight_boost(3., 0., &lit);
+        assert!(boost > 0.);
+        assert!((lightness(shade_wall(30., 0., 1., 0.25, &lit)) - 0.25).abs() < 1e-4, "out of the flashlight's range");
+        assert!((lightness(shade_wall(3., 0., 0.05, 0.25, &lit)) - (0.25 + boost)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn palette_output_stays_within_the_palette() {
+        let palette = vec![Color::BLACK, Color::rgb(0.5, 0.2, 0.1), Color::WHITE];
+        let viewport = Viewport { width: 320., height: 200., scale: Vec2::ONE, center: Vec2::ZERO, pixel_snap: false };
+        let mut view = ViewTransform { rotation: Vec2::X, offset: Vec2::ZERO, horizon: 0., viewport, palette: palette.clone(), dither: false };
+
+        let colors = [Color::rgb(0.3, 0.3, 0.3), Color::rgb(0.9, 0.1, 0.6), Color::rgba(0.2, 0.8, 0.4, 0.5)];
+        for dither in [false, true] {
+            view.dither = dither;
+            for color in colors {
+                assert!(palette.iter().any(|entry| entry.with_a(color.a()) == view.quantize(color)));
+                for x in 0..4 {
+                    for (_, _, run) in view.palette_runs(x as f32, 0., 16., color) {
+                        // palette colors come out with the drawn color's alpha
+                        assert!(palette.iter().any(|entry| entry.with_a(color.a()) == run), "{run:?} isn't in the palette");
+                    }
+                }
+            }
+        }
 
-    if DEBUG_MAP_MODE {
-        gizmos.arrow_2d(Vec2::ZERO, Vec2::X * scale, Color::GRAY);
-        gizmos.arrow_2d(Vec2::ZERO, Vec2::Y * scale, Color::GRAY);
+        // without a palette colors are drawn as they are
+        view.palette.clear();
+        assert_eq!(view.palette_runs(0., 0., 16., colors[1]), vec![(0., 16., colors[1])]);
+    }
+
+    #[test]
+    fn opposite_keys_cancel_out_without_nan() {
+        let keys = KeyBindings::default();
+        for held in [[keys.forward, keys.back], [keys.strafe_left, keys.strafe_right]] {
+            let pose = walk_from_start(&MovementConfig::default(), &held);
+            assert!(pose.position.is_finite() && pose.rotation.is_finite());
+            assert_eq!(pose.position, Vec2::splat(0.5));
+        }
+    }
 
-        gizmos.linestrip_gradient_2d([
-            (player.position * scale + Vec2::from_angle(player.rotation) * scale / 3., Color::BLUE),
-            (player.position * scale - Vec2::from_angle(player.rotation) * scale / 6. + Vec2::from_angle(player.rotation + PI / 2.) * scale / 6., Color::RED),
-            (player.position * scale - Vec2::from_angle(player.rotation) * scale / 6. - Vec2::from_angle(player.rotation + PI / 2.) * scale / 6., Color::GREEN),
-            (player.position * scale + Vec2::from_angle(player.rotation) * scale / 3., Color::BLUE),
-        ]);
+    #[test]
+    fn column_table_matches_column_ray() {
+        let viewer = Pose { position: Vec2::new(3.5, -1.25), rotation: 2.1 };
+        let render_width = 320;
 
-        for w in &environment.walls {
-            gizmos.rect_2d(
-                (Vec2::new(w.0 as f32, w.1 as f32) + Vec2::splat(0.5)) * scale,
-                0.,
-                Vec2::splat(scale),
-                Color::WHITE,
-            );    
+        for camera_model in [CameraModel::PlanarColumns, CameraModel::AngularColumns] {
+            let render_config = RenderConfig { camera_model, ..default() };
+            let focal_length = camera_model.focal_length(render_width, render_config.fov);
+            let mut table = ColumnTable::default();
+            table.update(render_width, &render_config);
+
+            let cached = table.rays(&viewer, 1, &render_config);
+            assert_eq!(cached.len(), render_width as usize);
+            for ((column, origin, direction, angle), (_, expected_origin, expected_direction, expected_angle)) in cached.into_iter().zip(column_rays(&viewer, render_width, 1, &render_config)) {
+                assert_eq!((origin, angle), (expected_origin, expected_angle));
+                assert!(direction.distance(expected_direction) < 1e-5, "{camera_model:?} column {column}: {direction:?} against {expected_direction:?}");
+
+                let (height, expected_height) = (table.projected_height(column as usize, 2.5, focal_length), camera_model.projected_height(2.5, angle, focal_length));
+                assert!((height - expected_height).abs() < 1e-3 * expected_height, "{camera_model:?} column {column}: {height} against {expected_height}");
+            }
         }
     }
 
-    for column in 0..resolution.width() as i32 {
-        let focal = resolution.width() / (2. * (FOV / 2.).tan());
-        let angle = ((column as f32 - resolution.width() / 2.) / focal).atan();
-        let ray_direction = Vec2::from_angle(angle).rotate(Vec2::from_angle(player.rotation));
+    // the cached column trig against working it out again for every column, run with
+    // cargo test --release column_table_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn column_table_cost() {
+        use std::hint::black_box;
+        use std::time::Instant;
 
-        let wall_distance = raycast(&environment.walls, player.position, ray_direction);
+        let render_config = RenderConfig::default();
+        let render_width = 320;
+        let focal_length = render_config.camera_model.focal_length(render_width, render_config.fov);
+        let mut table = ColumnTable::default();
+        table.update(render_width, &render_config);
+        let frames = 2000;
 
-        if DEBUG_MAP_MODE {
-            if wall_distance.is_some() {
-                gizmos.line_2d(player.position * scale, (player.position + ray_direction * wall_distance.unwrap()) * scale, Color::GREEN);
+        let start = Instant::now();
+        for frame in 0..frames {
+            let viewer = Pose { position: Vec2::splat(0.5), rotation: frame as f32 * 0.01 };
+            for (column, _, direction, angle) in column_rays(&viewer, render_width, 1, &render_config) {
+                black_box((column, direction, render_config.camera_model.projected_height(black_box(3.), angle, focal_length)));
             }
-            else {
-                gizmos.line_2d(player.position * scale, (player.position + ray_direction * 100.) * scale, Color::RED);
+        }
+        let per_column = start.elapsed() / frames;
+
+        let start = Instant::now();
+        for frame in 0..frames {
+            let viewer = Pose { position: Vec2::splat(0.5), rotation: frame as f32 * 0.01 };
+            for (column, _, direction, _) in table.rays(&viewer, 1, &render_config) {
+                black_box((column, direction, table.projected_height(column as usize, black_box(3.), focal_length)));
             }
         }
-        else if wall_distance.is_some() {
-            let percieved_wall_size = resolution.height() / (wall_distance.unwrap() * angle.cos());
+        let cached = start.elapsed() / frames;
 
-            let wall_color = Color::hsl(0., 0., 3. / wall_distance.unwrap());
-            // let floor_color_far = Color::hsl(0., 0., 1.0 - (resolution.height() - percieved_wall_size) / resolution.height());
-            // let floor_color_near = Color::hsl(0., 0., 1.0);
+        println!("{per_column:?} with column_ray, {cached:?} with the ColumnTable, per {render_width} column frame");
+    }
 
-            gizmos.line_2d(Vec2::new(resolution.width() / 2. - column as f32, -percieved_wall_size / 2.), 
-                            Vec2::new(resolution.width() / 2. - column as f32, percieved_wall_size / 2.), wall_color);
+    #[test]
+    fn holding_forward_and_strafe_slides_along_the_north_wall() {
+        let levels = sample_levels();
+        let environment = levels.active();
+        let movement_config = MovementConfig::default();
+        let keys = KeyBindings::default();
+        let mut keyboard = ButtonInput::<KeyCode>::default();
+        keyboard.press(keys.forward);
+        keyboard.press(keys.strafe_right);
 
-            // gizmos.linestrip_gradient_2d([
-            //     (Vec2::new(resolution.width() / 2. - column as f32, -percieved_wall_size / 2.), floor_color_far),
-            //     (Vec2::new(resolution.width() / 2. - column as f32, -resolution.height() / 2.), floor_color_near),
-            // ]);
-        
+        // facing the first level's north wall, whose face is at y = 2, and strafing east along it
+        let start = Vec2::new(-2., 0.5);
+        let mut pose = Pose { position: start, rotation: PI / 2. };
+        for _ in 0..60 {
+            walk(&mut pose, environment, &movement_config, keys.walk_keys(), &keyboard, 1. / 60.);
         }
+
+        assert!((pose.position.y - (2. - PLAYER_RADIUS)).abs() < 1e-3, "not against the wall: {:?}", pose.position);
+        assert!(pose.position.x > start.x + 1.5, "didn't slide along it: {:?}", pose.position);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn configured_enemy_cells_skip_walls_and_the_player() {
+        let environment = Environment::new([(1, 0), (3, 0)]);
+        let enemy_config = EnemyConfig { count: 2, spawn_cells: Some(vec![(0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0)]), ..default() };
+
+        assert_eq!(enemy_cells(&mut GameRng(StdRng::seed_from_u64(0)), &environment, (0, 0), &enemy_config), vec![(2, 0), (4, 0)]);
+    }
+}